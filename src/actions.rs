@@ -0,0 +1,441 @@
+use async_trait::async_trait;
+use colored::*;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::error::Error;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::{Arc, LazyLock};
+use crate::llm_client::{ChatMessage, LLMClient, LLMResponse};
+use crate::memory::{MemoryKind, MemoryStore};
+use crate::prompt::{instruction_budget, task_lines, ContextSection, PromptBuilder, ACTION_CONTEXT_PRIORITY, DEPENDENCY_PRIORITY, MEMORY_PRIORITY, TASKS_PRIORITY};
+use crate::task_manager::{Task, TaskManager, TaskStatus};
+use crate::templates::TemplateLibrary;
+use crate::tools::{ToolContext, ToolRegistry};
+
+/// Number of log lines handed to the LLM by the log based actions.
+pub const RECENT_LOG_LINES: usize = 50;
+
+/// Everything an action handler may need while executing a task.
+pub struct ActionContext<'a> {
+    pub task_manager: &'a TaskManager,
+    pub llm_client: &'a LLMClient,
+    /// Tools the LLM may call from `ask_with_tools`.
+    pub tools: &'a ToolRegistry,
+    /// Templates replacing the built-in prompts of the actions.
+    pub templates: &'a TemplateLibrary,
+    /// Actions a task may be given.
+    pub actions: &'a ActionRegistry,
+    /// Memory searched for context related to the prompt, if configured.
+    pub memory: Option<&'a MemoryStore>,
+    /// Results of the tasks listed in the task's `depends_on`.
+    pub dependency_results: Vec<(Task, String)>,
+}
+
+impl ActionContext<'_> {
+    /// Sends `prompt`, after the dependency results and `context`, to the LLM
+    /// under the persona and generation options configured for the task.
+    /// Context that doesn't fit the model's context window is cut.
+    pub async fn ask(&self, task: &Task, prompt: &str, context: Vec<ContextSection>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let messages = self.messages(task, prompt, context).await;
+        Ok(self.llm_client.chat(&messages, Some(task)).await?.into())
+    }
+
+    /// Like `ask`, but the reply is JSON validated against `schema`, with the
+    /// model asked to repair invalid replies. Fails the task if it can't.
+    pub async fn ask_json(&self, task: &Task, prompt: &str, context: Vec<ContextSection>, schema: &Value) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let messages = self.messages(task, prompt, context).await;
+        let (response, _) = self.llm_client.chat_json(&messages, Some(task), schema).await?;
+        Ok(response.into())
+    }
+
+    /// Like `ask`, but lets the LLM call the registered tools before answering.
+    pub async fn ask_with_tools(&self, task: &Task, prompt: &str, context: Vec<ContextSection>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let ctx = ToolContext {
+            task_manager: self.task_manager,
+            llm_client: self.llm_client,
+        };
+        Ok(self.tools.run(self.messages(task, prompt, context).await, Some(task), &ctx).await?.into())
+    }
+
+    /// The prompt template of the task's action, if there is one, replaces
+    /// `prompt` and `context`.
+    async fn messages(&self, task: &Task, prompt: &str, mut context: Vec<ContextSection>) -> Vec<ChatMessage> {
+        let budget = self.llm_client.prompt_budget(Some(task)).await;
+        let system = self.llm_client.system_prompt(Some(&task.action));
+        let mut prompt = prompt.to_string();
+        if let Some(template) = self.templates.get(&task.action, None) {
+            debug!("Using prompt template {} v{} for task {}", template.name, template.version, task.id);
+            prompt = self.templates.render(&template, task, self.task_manager, instruction_budget(budget, system)).await;
+            context.clear();
+        }
+        let dependency_results: Vec<String> = self.dependency_results.iter()
+            .map(|(task, result)| format!("Result of \"{}\":\n{}", task.description, result))
+            .collect();
+        let memories = match self.memory {
+            Some(memory) => memory.related(&format!("{}\n{}", task.description, prompt), Some(task)).await,
+            None => Vec::new(),
+        };
+        let (messages, report) = PromptBuilder::new(budget, &prompt)
+            .with_system(system)
+            .with_section(ContextSection::new("Results of the tasks this one depends on", DEPENDENCY_PRIORITY, dependency_results))
+            .with_section(ContextSection::new("Related memories", MEMORY_PRIORITY, memories))
+            .with_sections(context)
+            .build();
+        report.log(&format!("task {}", task.id));
+        messages
+    }
+}
+
+/// What an action produced, with the LLM usage behind it if it called the LLM.
+#[derive(Debug, Clone)]
+pub struct ActionOutput {
+    pub text: String,
+    pub model: Option<String>,
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+}
+
+impl From<String> for ActionOutput {
+    fn from(text: String) -> Self {
+        ActionOutput {
+            text,
+            model: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+        }
+    }
+}
+
+impl From<LLMResponse> for ActionOutput {
+    fn from(response: LLMResponse) -> Self {
+        ActionOutput {
+            text: response.text,
+            model: Some(response.model),
+            prompt_tokens: response.prompt_tokens,
+            completion_tokens: response.completion_tokens,
+        }
+    }
+}
+
+#[async_trait]
+pub trait ActionHandler: Send + Sync {
+    async fn handle(&self, task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>>;
+
+    /// Whether the action calls the LLM, and so waits while the circuit breaker is open.
+    fn uses_llm(&self) -> bool {
+        true
+    }
+
+    /// What the action's output is remembered as. Only the output of actions
+    /// that use the LLM is remembered.
+    fn memory_kind(&self) -> MemoryKind {
+        MemoryKind::TaskResult
+    }
+
+    /// Whether the action lets the LLM call the tools, and so may queue tasks.
+    fn uses_tools(&self) -> bool {
+        false
+    }
+}
+
+/// Maps `Task.action` names to the handler that executes them.
+#[derive(Clone, Default)]
+pub struct ActionRegistry {
+    handlers: HashMap<String, Arc<dyn ActionHandler>>,
+}
+
+impl ActionRegistry {
+    pub fn new() -> Self {
+        ActionRegistry {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Registry with a handler for every action seeded at startup.
+    pub fn with_builtin_actions() -> Self {
+        let mut registry = ActionRegistry::new();
+        registry.register("routine_check", RoutineCheck);
+        registry.register("check_status", CheckStatus);
+        registry.register("display_redis_data", DisplayRedisData);
+        registry.register("start_llm_communications", StartLlmCommunications);
+        registry.register("comment_last_logs", CommentLastLogs);
+        registry.register("take_improvement_actions", TakeImprovementActions);
+        registry.register("write_detailed_report", WriteDetailedReport);
+        registry
+    }
+
+    pub fn register<H: ActionHandler + 'static>(&mut self, action: &str, handler: H) {
+        self.handlers.insert(action.to_string(), Arc::new(handler));
+    }
+
+    pub fn actions(&self) -> Vec<String> {
+        let mut actions: Vec<String> = self.handlers.keys().cloned().collect();
+        actions.sort();
+        actions
+    }
+
+    /// Actions the `add_task` tool may queue: those that don't call the tools
+    /// themselves, so a tool loop can't queue more tool loops.
+    pub fn queueable_by_tools(&self) -> Vec<String> {
+        let mut actions: Vec<String> = self.handlers.iter()
+            .filter(|(_, handler)| !handler.uses_tools())
+            .map(|(action, _)| action.clone())
+            .collect();
+        actions.sort();
+        actions
+    }
+
+    pub fn contains(&self, action: &str) -> bool {
+        self.handlers.contains_key(action)
+    }
+
+    pub fn uses_llm(&self, action: &str) -> bool {
+        self.handlers.get(action).is_some_and(|handler| handler.uses_llm())
+    }
+
+    /// What the output of `action` is remembered as, `None` if it isn't.
+    pub fn memory_kind(&self, action: &str) -> Option<MemoryKind> {
+        self.handlers.get(action)
+            .filter(|handler| handler.uses_llm())
+            .map(|handler| handler.memory_kind())
+    }
+
+    pub async fn dispatch(&self, task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        match self.handlers.get(&task.action) {
+            Some(handler) => {
+                debug!("Dispatching task {:?} to action handler {}", task.description, task.action);
+                handler.handle(task, ctx).await
+            }
+            None => Err(format!("Unknown action: {}", task.action).into()),
+        }
+    }
+}
+
+impl std::fmt::Debug for ActionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActionRegistry")
+            .field("actions", &self.actions())
+            .finish()
+    }
+}
+
+/// Returns the last `count` lines of the application log file.
+pub fn read_recent_logs(count: usize) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let contents = std::fs::read_to_string(crate::LOG_FILE)?;
+    let lines: Vec<&str> = contents.lines().collect();
+    let start = lines.len().saturating_sub(count);
+    Ok(lines[start..].iter().map(|line| line.to_string()).collect())
+}
+
+fn describe_tasks(tasks: &[Task]) -> String {
+    if tasks.is_empty() {
+        return "none".to_string();
+    }
+    tasks.iter()
+        .map(|task| format!("- {} ({}, {:?})", task.description, task.action, task.status))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Lightweight liveness probe queued by the core loop every few seconds.
+struct RoutineCheck;
+
+#[async_trait]
+impl ActionHandler for RoutineCheck {
+    async fn handle(&self, _task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        ctx.task_manager.check_redis_connection().await?;
+        Ok("Routine check OK".to_string().into())
+    }
+
+    fn uses_llm(&self) -> bool {
+        false
+    }
+}
+
+/// Checks the Redis and LLM connections and reports the queue state.
+struct CheckStatus;
+
+#[async_trait]
+impl ActionHandler for CheckStatus {
+    async fn handle(&self, _task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let redis_status = ctx.task_manager.check_redis_connection().await.is_ok();
+        info!("Redis connection: {}", if redis_status { "OK" } else { "Failed" });
+
+        let llm_status = ctx.llm_client.check_llm_connection().await.is_ok();
+        info!("LLM connection: {}", if llm_status { "OK" } else { "Failed" });
+
+        let tasks = ctx.task_manager.get_tasks().await;
+        let pending = tasks.iter().filter(|task| task.status == TaskStatus::Pending).count();
+
+        if !redis_status || !llm_status {
+            return Err(format!("Health check failed: Redis {}, LLM {}",
+                if redis_status { "OK" } else { "Failed" },
+                if llm_status { "OK" } else { "Failed" }).into());
+        }
+        Ok(format!("Redis connection: OK, LLM connection: OK, Pending tasks: {}", pending).into())
+    }
+
+    // Only probes the server, so it keeps reporting while the circuit breaker is open
+    fn uses_llm(&self) -> bool {
+        false
+    }
+}
+
+/// Dumps the task data currently held in Redis.
+struct DisplayRedisData;
+
+#[async_trait]
+impl ActionHandler for DisplayRedisData {
+    async fn handle(&self, _task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let tasks = ctx.task_manager.get_tasks().await;
+        let completed = ctx.task_manager.get_completed_tasks().await;
+        let report = format!("Queued tasks ({}):\n{}\nCompleted tasks ({}):\n{}",
+            tasks.len(), describe_tasks(&tasks), completed.len(), describe_tasks(&completed));
+        println!("{}", report.cyan());
+        Ok(report.into())
+    }
+
+    fn uses_llm(&self) -> bool {
+        false
+    }
+}
+
+/// Starts the "conscious chain of thoughts" by summarizing the latest logs.
+/// The LLM may use the tools to look further into tasks, results and logs.
+struct StartLlmCommunications;
+
+#[async_trait]
+impl ActionHandler for StartLlmCommunications {
+    async fn handle(&self, task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let logs = read_recent_logs(RECENT_LOG_LINES)?;
+        let prompt = "You are the conscious part of an autonomous agent. Summarize what the agent has been doing based on these recent logs.";
+        ctx.ask_with_tools(task, prompt, vec![ContextSection::recent("Recent logs", ACTION_CONTEXT_PRIORITY, logs)]).await
+    }
+
+    fn uses_tools(&self) -> bool {
+        true
+    }
+}
+
+/// Has the LLM comment on the latest log lines.
+struct CommentLastLogs;
+
+#[async_trait]
+impl ActionHandler for CommentLastLogs {
+    async fn handle(&self, task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let logs = read_recent_logs(RECENT_LOG_LINES)?;
+        let prompt = "Comment on these application logs. Point out errors, warnings and anything unusual.";
+        ctx.ask(task, prompt, vec![ContextSection::recent("Recent logs", ACTION_CONTEXT_PRIORITY, logs)]).await
+    }
+}
+
+/// Has the LLM review past work, decide what to repeat or avoid and propose
+/// new tasks, which are added to the queue.
+struct TakeImprovementActions;
+
+/// New tasks added from one review; the rest of the proposals are ignored.
+const MAX_PROPOSED_TASKS: usize = 5;
+
+/// Output of `take_improvement_actions`. The proposed actions are restricted
+/// to the registered ones when the prompt is sent.
+static IMPROVEMENT_SCHEMA: LazyLock<Value> = LazyLock::new(|| json!({
+    "type": "object",
+    "properties": {
+        "went_well": { "type": "array", "items": { "type": "string" } },
+        "mistakes": { "type": "array", "items": { "type": "string" } },
+        "new_tasks": {
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "description": { "type": "string" },
+                    "action": { "type": "string" },
+                    "priority": { "type": "integer" }
+                },
+                "required": ["description", "action"]
+            }
+        },
+        "questions": { "type": "array", "items": { "type": "string" } }
+    },
+    "required": ["went_well", "mistakes", "new_tasks", "questions"]
+}));
+
+/// A task proposed in the `new_tasks` of an improvement review.
+#[derive(Debug, Deserialize)]
+struct ProposedTask {
+    description: String,
+    action: String,
+    #[serde(default)]
+    priority: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImprovementReview {
+    new_tasks: Vec<ProposedTask>,
+}
+
+#[async_trait]
+impl ActionHandler for TakeImprovementActions {
+    async fn handle(&self, task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let completed = ctx.task_manager.get_completed_tasks().await;
+        let queued = ctx.task_manager.get_tasks().await;
+        let prompt = "Analyze these results. Write what went well and should be replicated, \
+                      what was a bad decision and should be remembered, propose new tasks and list the questions that need human feedback.";
+        let context = vec![
+            ContextSection::new("Completed tasks", ACTION_CONTEXT_PRIORITY, task_lines(&completed)),
+            ContextSection::new("Queued tasks", TASKS_PRIORITY, task_lines(&queued)),
+        ];
+        // A review proposing another review would never end
+        let proposable: Vec<String> = ctx.actions.actions().into_iter()
+            .filter(|action| action != &task.action)
+            .collect();
+        let mut schema = IMPROVEMENT_SCHEMA.clone();
+        schema["properties"]["new_tasks"]["items"]["properties"]["action"]["enum"] = json!(proposable);
+        let output = ctx.ask_json(task, prompt, context, &schema).await?;
+
+        let review: ImprovementReview = serde_json::from_str(&output.text)?;
+        for proposed in review.new_tasks.into_iter().take(MAX_PROPOSED_TASKS) {
+            if !proposable.contains(&proposed.action) {
+                warn!("Ignoring proposed task \"{}\" with action {}", proposed.description, proposed.action);
+                continue;
+            }
+            let new_task = Task::new(&proposed.description, &proposed.action, false)
+                .with_generated_priority(proposed.priority.unwrap_or(0));
+            match ctx.task_manager.add_task(new_task).await {
+                Ok(id) => info!("Added proposed task {}: {}", id, proposed.description),
+                Err(e) => warn!("Failed to add proposed task \"{}\": {}", proposed.description, e),
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Has the LLM write a report of the concepts and behaviors learned so far.
+struct WriteDetailedReport;
+
+#[async_trait]
+impl ActionHandler for WriteDetailedReport {
+    async fn handle(&self, task: &Task, ctx: &ActionContext<'_>) -> Result<ActionOutput, Box<dyn Error + Send + Sync>> {
+        let completed = ctx.task_manager.get_completed_tasks().await;
+        let prompt = "Write a detailed report of the concepts and behaviors learned so far.";
+        ctx.ask(task, prompt, vec![ContextSection::new("Completed tasks", ACTION_CONTEXT_PRIORITY, task_lines(&completed))]).await
+    }
+
+    fn memory_kind(&self) -> MemoryKind {
+        MemoryKind::Report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tools_cannot_queue_actions_that_call_the_tools() {
+        let registry = ActionRegistry::with_builtin_actions();
+        let queueable = registry.queueable_by_tools();
+        assert!(!queueable.contains(&"start_llm_communications".to_string()));
+        assert_eq!(queueable.len(), registry.actions().len() - 1);
+    }
+}
@@ -0,0 +1,183 @@
+use async_trait::async_trait;
+use futures::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::llm_client::{ChatMessage, GenerationOptions, LLMResponse, ChunkStream};
+use crate::tools::ToolDefinition;
+
+mod ollama;
+mod openai;
+mod pool;
+
+pub use ollama::OllamaBackend;
+pub use openai::OpenAiBackend;
+pub use pool::{EndpointConfig, EndpointStatus, PoolBackend};
+
+/// Model metadata as reported by the server. Fields a backend can't provide are `None`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub format: Option<String>,
+    pub family: Option<String>,
+    pub parameter_size: Option<String>,
+    pub quantization_level: Option<String>,
+    /// Size of the model files, in bytes.
+    pub size: Option<u64>,
+    /// Maximum number of tokens the model accepts in its context window.
+    pub context_length: Option<u64>,
+    /// Context window the server runs the model with when a request sets no
+    /// `num_ctx`, if it differs from `context_length`.
+    pub default_context_length: Option<u64>,
+}
+
+/// A server able to run LLM generations.
+#[async_trait]
+pub trait LlmBackend: Send + Sync + Debug {
+    /// Short name used in logs and status output.
+    fn name(&self) -> &'static str;
+
+    /// Sends a conversation. With a `schema`, the reply is constrained to JSON
+    /// following that JSON schema.
+    async fn chat(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions, schema: Option<&Value>) -> Result<LLMResponse, Box<dyn Error + Send + Sync>>;
+
+    /// Whether `chat_with_tools` is implemented.
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    /// Like `chat`, but offers the model `tools`. The calls it makes are
+    /// returned in `LLMResponse::tool_calls`.
+    async fn chat_with_tools(&self, _model: &str, _messages: &[ChatMessage], _options: &GenerationOptions, _tools: &[ToolDefinition]) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        Err(format!("The {} backend does not support tools", self.name()).into())
+    }
+
+    /// Like `chat`, but yields the reply token by token, then the usage
+    /// reported for it if the server reports any.
+    async fn chat_stream(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions) -> Result<ChunkStream, Box<dyn Error + Send + Sync>>;
+
+    async fn model_info(&self, model: &str) -> Result<ModelInfo, Box<dyn Error + Send + Sync>>;
+
+    /// Models installed on the server.
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, Box<dyn Error + Send + Sync>>;
+
+    /// Returns one embedding vector per input, in order.
+    async fn embed(&self, model: &str, input: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn Error + Send + Sync>>;
+
+    /// Checks the server answers for `model`. Pools check each of their endpoints.
+    async fn health_check(&self, model: &str) -> Result<ModelInfo, Box<dyn Error + Send + Sync>> {
+        self.model_info(model).await
+    }
+
+    /// State of the servers behind the backend, empty for a single server.
+    fn endpoints(&self) -> Vec<EndpointStatus> {
+        Vec::new()
+    }
+}
+
+/// Backend implementations selectable with `llm.backend` in `config.toml`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Ollama,
+    /// Any server implementing OpenAI's `/v1` API (llama.cpp server, vLLM, LM Studio).
+    OpenAI,
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "ollama" => Ok(BackendKind::Ollama),
+            "openai" => Ok(BackendKind::OpenAI),
+            _ => Err(format!("Unknown LLM backend: {}", kind)),
+        }
+    }
+}
+
+/// HTTP timeouts of LLM requests, read from `[llm.timeouts]` and overridden
+/// per action in `[llm.action_timeouts.<action>]`. Unset means no timeout.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LlmTimeouts {
+    /// Time to establish the connection.
+    pub connect_secs: Option<u64>,
+    /// Longest wait for the next chunk of the response, so a streaming
+    /// generation may run longer as long as tokens keep coming.
+    pub read_secs: Option<u64>,
+    /// Time for the whole request, including the response body.
+    pub total_secs: Option<u64>,
+}
+
+impl LlmTimeouts {
+    /// These timeouts with every field set in `overrides` replaced.
+    pub fn merge(&self, overrides: &LlmTimeouts) -> LlmTimeouts {
+        LlmTimeouts {
+            connect_secs: overrides.connect_secs.or(self.connect_secs),
+            read_secs: overrides.read_secs.or(self.read_secs),
+            total_secs: overrides.total_secs.or(self.total_secs),
+        }
+    }
+
+    fn http_client(&self) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder();
+        if let Some(connect_secs) = self.connect_secs {
+            builder = builder.connect_timeout(Duration::from_secs(connect_secs));
+        }
+        if let Some(read_secs) = self.read_secs {
+            builder = builder.read_timeout(Duration::from_secs(read_secs));
+        }
+        if let Some(total_secs) = self.total_secs {
+            builder = builder.timeout(Duration::from_secs(total_secs));
+        }
+        builder.build().expect("Failed to build the LLM HTTP client")
+    }
+}
+
+pub fn create_backend(kind: BackendKind, url: &str, api_key: Option<String>, timeouts: &LlmTimeouts) -> Arc<dyn LlmBackend> {
+    match kind {
+        BackendKind::Ollama => Arc::new(OllamaBackend::new(url, timeouts)),
+        BackendKind::OpenAI => Arc::new(OpenAiBackend::new(url, api_key, timeouts)),
+    }
+}
+
+/// Whether `error` means the endpoint couldn't be reached or didn't answer in
+/// time, as opposed to the server rejecting the request.
+pub fn is_endpoint_failure(error: &(dyn Error + Send + Sync + 'static)) -> bool {
+    error.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect() || e.is_timeout() || e.is_request())
+}
+
+/// Splits a streamed response body into lines, whatever the chunk boundaries.
+fn response_lines(response: reqwest::Response) -> impl Stream<Item = Result<Vec<u8>, Box<dyn Error + Send + Sync>>> + Send {
+    stream::unfold((response.bytes_stream(), Vec::new(), false), |(mut bytes, mut buffer, mut finished)| async move {
+        loop {
+            if let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=newline).collect();
+                return Some((Ok(line), (bytes, buffer, finished)));
+            }
+            if finished {
+                if buffer.is_empty() {
+                    return None;
+                }
+                let line = std::mem::take(&mut buffer);
+                return Some((Ok(line), (bytes, buffer, finished)));
+            }
+            match bytes.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    finished = true;
+                    buffer.clear();
+                    let error: Box<dyn Error + Send + Sync> = e.into();
+                    return Some((Err(error), (bytes, buffer, finished)));
+                }
+                None => finished = true,
+            }
+        }
+    })
+}
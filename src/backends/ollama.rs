@@ -0,0 +1,332 @@
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use crate::llm_client::{ChatMessage, GenerationOptions, LLMResponse, ChunkStream, StreamChunk};
+use crate::tools::ToolDefinition;
+use super::{response_lines, LlmBackend, LlmTimeouts, ModelInfo};
+
+/// Context window Ollama runs a model with when neither the request nor the
+/// Modelfile sets `num_ctx`.
+const OLLAMA_DEFAULT_NUM_CTX: u64 = 2048;
+
+#[derive(Debug, Serialize)]
+struct ChatInput<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    stream: bool,
+    /// Ollama takes the generation options under their own names.
+    options: &'a GenerationOptions,
+    /// JSON schema the reply must follow.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolSpec<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ToolSpec<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: &'a ToolDefinition,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatOutput {
+    message: ChatMessage,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
+    /// Durations are reported in nanoseconds.
+    #[serde(default)]
+    total_duration: Option<u64>,
+    #[serde(default)]
+    load_duration: Option<u64>,
+    #[serde(default)]
+    eval_duration: Option<u64>,
+}
+
+/// One line of the streamed NDJSON chat response. The last one has `done`
+/// set and carries the token counts and durations of the whole reply.
+#[derive(Debug, Deserialize)]
+struct ChatStreamChunk {
+    #[serde(default)]
+    message: Option<ChatMessage>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
+    /// Durations are reported in nanoseconds.
+    #[serde(default)]
+    total_duration: Option<u64>,
+    #[serde(default)]
+    load_duration: Option<u64>,
+    #[serde(default)]
+    eval_duration: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelInfoRequest<'a> {
+    name: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelInfoResponse {
+    #[serde(default)]
+    details: ModelDetails,
+    /// Architecture specific keys such as `llama.context_length`.
+    #[serde(default)]
+    model_info: HashMap<String, serde_json::Value>,
+    /// The Modelfile's `PARAMETER` lines, such as `num_ctx 8192`.
+    #[serde(default)]
+    parameters: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ModelDetails {
+    #[serde(default)]
+    format: Option<String>,
+    #[serde(default)]
+    family: Option<String>,
+    #[serde(default)]
+    parameter_size: Option<String>,
+    #[serde(default)]
+    quantization_level: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagsResponse {
+    models: Vec<TaggedModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TaggedModel {
+    name: String,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    details: ModelDetails,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbedInput<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbedOutput {
+    embeddings: Vec<Vec<f32>>,
+}
+
+fn nanos_to_millis(nanos: u64) -> u64 {
+    nanos / 1_000_000
+}
+
+/// The `num_ctx` set in the `parameters` of `/api/show`, one `name value` per line.
+fn parse_num_ctx(parameters: &str) -> Option<u64> {
+    parameters.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("num_ctx"), Some(value)) => value.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Ollama's native `/api` endpoints.
+#[derive(Debug, Clone)]
+pub struct OllamaBackend {
+    base_url: String,
+    client: Client,
+}
+
+impl OllamaBackend {
+    /// `url` is the server root, e.g. `http://localhost:11434`. A trailing
+    /// `/api/generate` is accepted for compatibility with older configs.
+    pub fn new(url: &str, timeouts: &LlmTimeouts) -> Self {
+        let base_url = url.trim_end_matches('/').trim_end_matches("/api/generate").trim_end_matches('/');
+        OllamaBackend {
+            base_url: base_url.to_string(),
+            client: timeouts.http_client(),
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/api/{}", self.base_url, path)
+    }
+
+    async fn send_chat(&self, input: &ChatInput<'_>) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        let response = self.client.post(self.endpoint("chat"))
+            .json(input)
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            let output = response.json::<ChatOutput>().await?;
+            Ok(LLMResponse {
+                text: output.message.content,
+                model: output.model.unwrap_or_else(|| input.model.to_string()),
+                prompt_tokens: output.prompt_eval_count,
+                completion_tokens: output.eval_count,
+                total_duration_ms: output.total_duration.map(nanos_to_millis),
+                load_duration_ms: output.load_duration.map(nanos_to_millis),
+                eval_duration_ms: output.eval_duration.map(nanos_to_millis),
+                tool_calls: output.message.tool_calls,
+            })
+        } else {
+            Err(format!("LLM processing failed: {} - {}", response.status(), response.text().await?).into())
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OllamaBackend {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    async fn chat(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions, schema: Option<&Value>) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        self.send_chat(&ChatInput { model, messages, stream: false, options, format: schema, tools: Vec::new() }).await
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    async fn chat_with_tools(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions, tools: &[ToolDefinition]) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        let tools = tools.iter().map(|function| ToolSpec { kind: "function", function }).collect();
+        self.send_chat(&ChatInput { model, messages, stream: false, options, format: None, tools }).await
+    }
+
+    async fn chat_stream(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions) -> Result<ChunkStream, Box<dyn Error + Send + Sync>> {
+        let input = ChatInput { model, messages, stream: true, options, format: None, tools: Vec::new() };
+        let response = self.client.post(self.endpoint("chat"))
+            .json(&input)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("LLM processing failed: {} - {}", response.status(), response.text().await?).into());
+        }
+
+        let model = model.to_string();
+        let chunks = response_lines(response).filter_map(move |line| {
+            let model = model.clone();
+            async move {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => return Some(Err(e)),
+                };
+                if line.iter().all(|byte| byte.is_ascii_whitespace()) {
+                    return None;
+                }
+                match serde_json::from_slice::<ChatStreamChunk>(&line) {
+                    Ok(ChatStreamChunk { error: Some(error), .. }) => Some(Err(format!("LLM processing failed: {}", error).into())),
+                    Ok(chunk) if chunk.done => Some(Ok(StreamChunk::Done(LLMResponse {
+                        text: String::new(),
+                        model: chunk.model.unwrap_or(model),
+                        prompt_tokens: chunk.prompt_eval_count,
+                        completion_tokens: chunk.eval_count,
+                        total_duration_ms: chunk.total_duration.map(nanos_to_millis),
+                        load_duration_ms: chunk.load_duration.map(nanos_to_millis),
+                        eval_duration_ms: chunk.eval_duration.map(nanos_to_millis),
+                        tool_calls: Vec::new(),
+                    }))),
+                    Ok(ChatStreamChunk { message: Some(message), .. }) if !message.content.is_empty() => Some(Ok(StreamChunk::Token(message.content))),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e.into())),
+                }
+            }
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    async fn model_info(&self, model: &str) -> Result<ModelInfo, Box<dyn Error + Send + Sync>> {
+        let response = self.client.post(self.endpoint("show"))
+            .json(&ModelInfoRequest { name: model })
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            let info = response.json::<ModelInfoResponse>().await?;
+            let context_length = info.model_info.iter()
+                .find(|(key, _)| key.ends_with(".context_length"))
+                .and_then(|(_, value)| value.as_u64());
+            let default_context_length = info.parameters.as_deref()
+                .and_then(parse_num_ctx)
+                .unwrap_or(OLLAMA_DEFAULT_NUM_CTX);
+            Ok(ModelInfo {
+                name: model.to_string(),
+                format: info.details.format,
+                family: info.details.family,
+                parameter_size: info.details.parameter_size,
+                quantization_level: info.details.quantization_level,
+                size: None,
+                context_length,
+                default_context_length: Some(default_context_length),
+            })
+        } else {
+            Err(format!("LLM endpoint returned status: {} - {}", response.status(), response.text().await?).into())
+        }
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, Box<dyn Error + Send + Sync>> {
+        let response = self.client.get(self.endpoint("tags"))
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            let tags = response.json::<TagsResponse>().await?;
+            Ok(tags.models.into_iter().map(|model| ModelInfo {
+                name: model.name,
+                format: model.details.format,
+                family: model.details.family,
+                parameter_size: model.details.parameter_size,
+                quantization_level: model.details.quantization_level,
+                size: model.size,
+                context_length: None,
+                default_context_length: None,
+            }).collect())
+        } else {
+            Err(format!("LLM endpoint returned status: {} - {}", response.status(), response.text().await?).into())
+        }
+    }
+
+    async fn embed(&self, model: &str, input: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn Error + Send + Sync>> {
+        let response = self.client.post(self.endpoint("embed"))
+            .json(&EmbedInput { model, input })
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(response.json::<EmbedOutput>().await?.embeddings)
+        } else {
+            Err(format!("LLM embedding failed: {} - {}", response.status(), response.text().await?).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_num_ctx_from_the_modelfile_parameters() {
+        let parameters = "stop                           \"<|eot_id|>\"\nnum_ctx                        8192\ntemperature                    0.7";
+        assert_eq!(parse_num_ctx(parameters), Some(8192));
+        assert_eq!(parse_num_ctx("temperature 0.7"), None);
+        assert_eq!(parse_num_ctx(""), None);
+    }
+}
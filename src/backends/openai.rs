@@ -0,0 +1,294 @@
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use crate::llm_client::{ChatMessage, GenerationOptions, LLMResponse, ChunkStream, StreamChunk};
+use super::{response_lines, LlmBackend, LlmTimeouts, ModelInfo};
+
+/// `GenerationOptions` under OpenAI's names. The API has no equivalent of `num_ctx`.
+#[derive(Debug, Serialize)]
+struct SamplingParams<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+}
+
+impl<'a> From<&'a GenerationOptions> for SamplingParams<'a> {
+    fn from(options: &'a GenerationOptions) -> Self {
+        SamplingParams {
+            temperature: options.temperature,
+            top_p: options.top_p,
+            // Ollama uses a negative num_predict for "no limit"
+            max_tokens: options.num_predict.filter(|max_tokens| *max_tokens > 0),
+            stop: options.stop.as_deref(),
+            seed: options.seed,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatInput<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    stream: bool,
+    #[serde(flatten)]
+    params: SamplingParams<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+    /// Asks for a last streamed chunk carrying the token counts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatOutput {
+    choices: Vec<ChatChoice>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct Usage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+}
+
+/// One `data:` event of a streamed chat completion. With `include_usage`,
+/// the last one has no choices and carries the token counts.
+#[derive(Debug, Deserialize)]
+struct ChatStreamChunk {
+    #[serde(default)]
+    choices: Vec<ChatStreamChoice>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatStreamChoice {
+    delta: ChatDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelObject {
+    id: String,
+    /// Not part of the OpenAI API, but reported by llama.cpp and vLLM.
+    #[serde(default)]
+    max_model_len: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelObject>,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingInput<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingOutput {
+    data: Vec<Embedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Embedding {
+    embedding: Vec<f32>,
+}
+
+/// Servers implementing OpenAI's `/v1` API.
+#[derive(Debug, Clone)]
+pub struct OpenAiBackend {
+    base_url: String,
+    api_key: Option<String>,
+    client: Client,
+}
+
+impl OpenAiBackend {
+    /// `url` is the server root, e.g. `http://localhost:8080`, with or without `/v1`.
+    pub fn new(url: &str, api_key: Option<String>, timeouts: &LlmTimeouts) -> Self {
+        let base_url = url.trim_end_matches('/').trim_end_matches("/v1");
+        OpenAiBackend {
+            base_url: base_url.to_string(),
+            api_key,
+            client: timeouts.http_client(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(method, format!("{}/v1/{}", self.base_url, path));
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn chat(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions, schema: Option<&Value>) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        let response = self.request(reqwest::Method::POST, "chat/completions")
+            .json(&ChatInput {
+                model,
+                messages,
+                stream: false,
+                params: options.into(),
+                response_format: schema.map(|schema| json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": "response", "schema": schema },
+                })),
+                stream_options: None,
+            })
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            let output = response.json::<ChatOutput>().await?;
+            let message = output.choices.into_iter().next()
+                .ok_or("LLM returned no choices")?
+                .message;
+            Ok(LLMResponse {
+                text: message.content,
+                model: output.model.unwrap_or_else(|| model.to_string()),
+                prompt_tokens: output.usage.as_ref().and_then(|usage| usage.prompt_tokens),
+                completion_tokens: output.usage.as_ref().and_then(|usage| usage.completion_tokens),
+                total_duration_ms: None,
+                load_duration_ms: None,
+                eval_duration_ms: None,
+                tool_calls: Vec::new(),
+            })
+        } else {
+            Err(format!("LLM processing failed: {} - {}", response.status(), response.text().await?).into())
+        }
+    }
+
+    async fn chat_stream(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions) -> Result<ChunkStream, Box<dyn Error + Send + Sync>> {
+        let response = self.request(reqwest::Method::POST, "chat/completions")
+            .json(&ChatInput {
+                model,
+                messages,
+                stream: true,
+                params: options.into(),
+                response_format: None,
+                stream_options: Some(json!({ "include_usage": true })),
+            })
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("LLM processing failed: {} - {}", response.status(), response.text().await?).into());
+        }
+
+        // Server-Sent Events: one `data: {...}` line per chunk, ended by `data: [DONE]`
+        let model = model.to_string();
+        let chunks = response_lines(response)
+            .take_while(|line| {
+                let done = matches!(line, Ok(line) if line.trim_ascii() == b"data: [DONE]");
+                async move { !done }
+            })
+            .filter_map(move |line| {
+                let model = model.clone();
+                async move {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    let data = line.trim_ascii().strip_prefix(b"data:")?.trim_ascii();
+                    match serde_json::from_slice::<ChatStreamChunk>(data) {
+                        Ok(ChatStreamChunk { usage: Some(usage), model: reported, .. }) => Some(Ok(StreamChunk::Done(LLMResponse {
+                            text: String::new(),
+                            model: reported.unwrap_or(model),
+                            prompt_tokens: usage.prompt_tokens,
+                            completion_tokens: usage.completion_tokens,
+                            total_duration_ms: None,
+                            load_duration_ms: None,
+                            eval_duration_ms: None,
+                            tool_calls: Vec::new(),
+                        }))),
+                        Ok(chunk) => chunk.choices.into_iter().next()
+                            .and_then(|choice| choice.delta.content)
+                            .filter(|content| !content.is_empty())
+                            .map(|content| Ok(StreamChunk::Token(content))),
+                        Err(e) => Some(Err(e.into())),
+                    }
+                }
+            });
+        Ok(Box::pin(chunks))
+    }
+
+    async fn model_info(&self, model: &str) -> Result<ModelInfo, Box<dyn Error + Send + Sync>> {
+        let response = self.request(reqwest::Method::GET, &format!("models/{}", model))
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            let info = response.json::<ModelObject>().await?;
+            Ok(ModelInfo {
+                name: info.id,
+                context_length: info.max_model_len,
+                ..ModelInfo::default()
+            })
+        } else {
+            Err(format!("LLM endpoint returned status: {} - {}", response.status(), response.text().await?).into())
+        }
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, Box<dyn Error + Send + Sync>> {
+        let response = self.request(reqwest::Method::GET, "models")
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            let models = response.json::<ModelList>().await?;
+            Ok(models.data.into_iter().map(|model| ModelInfo {
+                name: model.id,
+                context_length: model.max_model_len,
+                ..ModelInfo::default()
+            }).collect())
+        } else {
+            Err(format!("LLM endpoint returned status: {} - {}", response.status(), response.text().await?).into())
+        }
+    }
+
+    async fn embed(&self, model: &str, input: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn Error + Send + Sync>> {
+        let response = self.request(reqwest::Method::POST, "embeddings")
+            .json(&EmbeddingInput { model, input })
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            let output = response.json::<EmbeddingOutput>().await?;
+            Ok(output.data.into_iter().map(|embedding| embedding.embedding).collect())
+        } else {
+            Err(format!("LLM embedding failed: {} - {}", response.status(), response.text().await?).into())
+        }
+    }
+}
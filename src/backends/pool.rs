@@ -0,0 +1,261 @@
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use crate::llm_client::{ChatMessage, GenerationOptions, LLMResponse, ChunkStream};
+use crate::tools::ToolDefinition;
+use super::{create_backend, is_endpoint_failure, BackendKind, LlmBackend, LlmTimeouts, ModelInfo};
+
+/// One server of the pool, as listed in `llm.endpoints`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EndpointConfig {
+    pub url: String,
+    /// Share of the requests the endpoint takes relative to the others.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl EndpointConfig {
+    pub fn new(url: &str) -> Self {
+        EndpointConfig {
+            url: url.to_string(),
+            weight: default_weight(),
+        }
+    }
+}
+
+/// Snapshot of an endpoint, as shown in `/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointStatus {
+    pub url: String,
+    pub weight: u32,
+    pub healthy: bool,
+    /// Requests currently in flight.
+    pub outstanding: usize,
+}
+
+/// Health and load of an endpoint, shared by the pools built for every set of timeouts.
+#[derive(Debug)]
+struct EndpointState {
+    healthy: AtomicBool,
+    outstanding: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    config: EndpointConfig,
+    backend: Arc<dyn LlmBackend>,
+    state: Arc<EndpointState>,
+}
+
+impl Endpoint {
+    /// Load relative to the endpoint's weight, counting the request about to be sent.
+    fn load(&self) -> f64 {
+        (self.state.outstanding.load(Ordering::Relaxed) + 1) as f64 / self.config.weight.max(1) as f64
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.state.healthy.load(Ordering::Relaxed)
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        let was_healthy = self.state.healthy.swap(healthy, Ordering::Relaxed);
+        if was_healthy && !healthy {
+            warn!("LLM endpoint {} is down", self.config.url);
+        } else if !was_healthy && healthy {
+            info!("LLM endpoint {} is back up", self.config.url);
+        }
+    }
+
+    fn start_request(&self) -> OutstandingGuard {
+        self.state.outstanding.fetch_add(1, Ordering::Relaxed);
+        OutstandingGuard(self.state.clone())
+    }
+}
+
+/// Counts a request as outstanding until dropped, including when the request is cancelled.
+struct OutstandingGuard(Arc<EndpointState>);
+
+impl Drop for OutstandingGuard {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Spreads requests over several servers of the same kind. Each request goes
+/// to the healthy endpoint with the fewest outstanding requests for its
+/// weight, and fails over to the next one if the endpoint can't be reached.
+#[derive(Debug)]
+pub struct PoolBackend {
+    kind: BackendKind,
+    api_key: Option<String>,
+    endpoints: Vec<Endpoint>,
+}
+
+impl PoolBackend {
+    pub fn new(kind: BackendKind, endpoints: &[EndpointConfig], api_key: Option<String>, timeouts: &LlmTimeouts) -> Self {
+        PoolBackend {
+            kind,
+            endpoints: endpoints.iter()
+                .map(|config| Endpoint {
+                    config: config.clone(),
+                    backend: create_backend(kind, &config.url, api_key.clone(), timeouts),
+                    state: Arc::new(EndpointState {
+                        healthy: AtomicBool::new(true),
+                        outstanding: AtomicUsize::new(0),
+                    }),
+                })
+                .collect(),
+            api_key,
+        }
+    }
+
+    /// The same endpoints with other timeouts. Health and outstanding requests
+    /// are shared with this pool.
+    pub fn with_timeouts(&self, timeouts: &LlmTimeouts) -> Self {
+        PoolBackend {
+            kind: self.kind,
+            api_key: self.api_key.clone(),
+            endpoints: self.endpoints.iter()
+                .map(|endpoint| Endpoint {
+                    config: endpoint.config.clone(),
+                    backend: create_backend(self.kind, &endpoint.config.url, self.api_key.clone(), timeouts),
+                    state: endpoint.state.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Endpoints in the order they should be tried: healthy ones first, least
+    /// loaded first. Unhealthy ones are still tried last, in case they recovered.
+    fn candidates(&self) -> Vec<&Endpoint> {
+        let mut candidates: Vec<&Endpoint> = self.endpoints.iter().collect();
+        candidates.sort_by(|a, b| b.is_healthy().cmp(&a.is_healthy()).then(a.load().total_cmp(&b.load())));
+        candidates
+    }
+
+    /// Runs `call` on the candidates in turn until one answers.
+    async fn failover<'a, T>(&self, call: impl Fn(Arc<dyn LlmBackend>) -> BoxFuture<'a, Result<T, Box<dyn Error + Send + Sync>>>) -> Result<T, Box<dyn Error + Send + Sync>> {
+        let mut last_error = None;
+        for endpoint in self.candidates() {
+            let _guard = endpoint.start_request();
+            match call(endpoint.backend.clone()).await {
+                Err(e) if is_endpoint_failure(e.as_ref()) => {
+                    warn!("LLM endpoint {} failed, trying the next one: {}", endpoint.config.url, e);
+                    endpoint.set_healthy(false);
+                    last_error = Some(e);
+                }
+                result => {
+                    if result.is_ok() {
+                        endpoint.set_healthy(true);
+                    }
+                    return result;
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "No LLM endpoint configured".into()))
+    }
+}
+
+#[async_trait]
+impl LlmBackend for PoolBackend {
+    fn name(&self) -> &'static str {
+        match self.endpoints.first() {
+            Some(endpoint) => endpoint.backend.name(),
+            None => "pool",
+        }
+    }
+
+    async fn chat(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions, schema: Option<&Value>) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        self.failover(|backend| Box::pin(async move { backend.chat(model, messages, options, schema).await })).await
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.endpoints.iter().all(|endpoint| endpoint.backend.supports_tools())
+    }
+
+    async fn chat_with_tools(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions, tools: &[ToolDefinition]) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        self.failover(|backend| Box::pin(async move { backend.chat_with_tools(model, messages, options, tools).await })).await
+    }
+
+    async fn chat_stream(&self, model: &str, messages: &[ChatMessage], options: &GenerationOptions) -> Result<ChunkStream, Box<dyn Error + Send + Sync>> {
+        // Only opening the stream fails over; once tokens flow, the endpoint
+        // stays outstanding until the stream is dropped
+        let mut last_error = None;
+        for endpoint in self.candidates() {
+            let guard = endpoint.start_request();
+            match endpoint.backend.chat_stream(model, messages, options).await {
+                Ok(chunks) => {
+                    endpoint.set_healthy(true);
+                    let chunks = chunks.map(move |chunk| {
+                        let _ = &guard;
+                        chunk
+                    });
+                    return Ok(Box::pin(chunks));
+                }
+                Err(e) if is_endpoint_failure(e.as_ref()) => {
+                    warn!("LLM endpoint {} failed, trying the next one: {}", endpoint.config.url, e);
+                    endpoint.set_healthy(false);
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "No LLM endpoint configured".into()))
+    }
+
+    async fn model_info(&self, model: &str) -> Result<ModelInfo, Box<dyn Error + Send + Sync>> {
+        self.failover(|backend| Box::pin(async move { backend.model_info(model).await })).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, Box<dyn Error + Send + Sync>> {
+        self.failover(|backend| Box::pin(async move { backend.list_models().await })).await
+    }
+
+    async fn embed(&self, model: &str, input: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn Error + Send + Sync>> {
+        self.failover(|backend| Box::pin(async move { backend.embed(model, input).await })).await
+    }
+
+    /// Checks every endpoint, marking the ones that don't answer as down.
+    async fn health_check(&self, model: &str) -> Result<ModelInfo, Box<dyn Error + Send + Sync>> {
+        let mut healthy_info = None;
+        let mut last_error = None;
+        for endpoint in &self.endpoints {
+            match endpoint.backend.model_info(model).await {
+                Ok(info) => {
+                    endpoint.set_healthy(true);
+                    healthy_info.get_or_insert(info);
+                }
+                Err(e) => {
+                    warn!("LLM endpoint {} failed its health check: {}", endpoint.config.url, e);
+                    endpoint.set_healthy(false);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match healthy_info {
+            Some(info) => Ok(info),
+            None => Err(last_error.unwrap_or_else(|| "No LLM endpoint configured".into())),
+        }
+    }
+
+    fn endpoints(&self) -> Vec<EndpointStatus> {
+        self.endpoints.iter()
+            .map(|endpoint| EndpointStatus {
+                url: endpoint.config.url.clone(),
+                weight: endpoint.config.weight,
+                healthy: endpoint.is_healthy(),
+                outstanding: endpoint.state.outstanding.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
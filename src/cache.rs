@@ -0,0 +1,127 @@
+use log::error;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use crate::llm_client::{ChatMessage, GenerationOptions, LLMResponse};
+use crate::task_manager::Task;
+
+/// Hash of the hit and miss counters.
+const STATS_KEY: &str = "cache:stats";
+
+/// Response cache settings, read from `[llm.cache]` in `config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// How long a cached response is served.
+    pub ttl_secs: u64,
+    /// Actions whose LLM calls are cached. Nothing else is.
+    pub actions: Vec<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            ttl_secs: 3600,
+            actions: Vec::new(),
+        }
+    }
+}
+
+/// Cache counters, as shown in `/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+fn response_key(hash: &str) -> String {
+    format!("cache:response:{}", hash)
+}
+
+/// Serves repeated LLM calls from Redis: a call with the same backend, model,
+/// messages, generation options and schema as a cached one gets its response
+/// without occupying the server.
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+    redis_client: Client,
+    config: CacheConfig,
+}
+
+impl ResponseCache {
+    pub fn new(redis_client: Client, config: CacheConfig) -> Self {
+        ResponseCache { redis_client, config }
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        self.redis_client.get_multiplexed_async_connection().await
+    }
+
+    /// Whether the calls made for `task` are cached.
+    pub fn caches(&self, task: Option<&Task>) -> bool {
+        self.config.enabled && task.is_some_and(|task| self.config.actions.contains(&task.action))
+    }
+
+    /// Cache key of a call, hashing everything that shapes its response.
+    pub fn key(backend: &str, model: &str, messages: &[ChatMessage], options: &GenerationOptions, schema: Option<&Value>) -> String {
+        let call = json!({
+            "backend": backend,
+            "model": model,
+            "messages": messages,
+            "options": options,
+            "schema": schema,
+        });
+        sha1_smol::Sha1::from(call.to_string()).digest().to_string()
+    }
+
+    /// The cached response for `key`, counting the hit or miss. Failures are
+    /// logged and count as misses, so the cache never breaks an LLM call.
+    pub async fn get(&self, key: &str) -> Option<LLMResponse> {
+        match self.try_get(key).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Failed to read the LLM response cache: {:?}", e);
+                None
+            }
+        }
+    }
+
+    async fn try_get(&self, key: &str) -> Result<Option<LLMResponse>, Box<dyn Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let response_json: Option<String> = con.get(response_key(key)).await?;
+        let response = match response_json {
+            Some(response_json) => Some(serde_json::from_str(&response_json)?),
+            None => None,
+        };
+        let counter = if response.is_some() { "hits" } else { "misses" };
+        let _: () = con.hincr(STATS_KEY, counter, 1).await?;
+        Ok(response)
+    }
+
+    pub async fn put(&self, key: &str, response: &LLMResponse) {
+        if let Err(e) = self.try_put(key, response).await {
+            error!("Failed to write the LLM response cache: {:?}", e);
+        }
+    }
+
+    async fn try_put(&self, key: &str, response: &LLMResponse) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let _: () = con.set_ex(response_key(key), serde_json::to_string(response)?, self.config.ttl_secs).await?;
+        Ok(())
+    }
+
+    pub async fn stats(&self) -> Result<CacheStats, Box<dyn Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let counters: HashMap<String, u64> = con.hgetall(STATS_KEY).await?;
+        Ok(CacheStats {
+            enabled: self.config.enabled,
+            hits: counters.get("hits").copied().unwrap_or(0),
+            misses: counters.get("misses").copied().unwrap_or(0),
+        })
+    }
+}
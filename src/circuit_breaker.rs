@@ -0,0 +1,215 @@
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Circuit breaker settings, read from `[llm.circuit_breaker]` in `config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed LLM calls that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial call is let through.
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            open_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Calls go through.
+    Closed,
+    /// Calls fail immediately until `open_until`.
+    Open,
+    /// A single trial call is in flight; its outcome closes or reopens the
+    /// circuit. Another trial is let through if it takes past `open_until`.
+    HalfOpen,
+}
+
+/// Snapshot of the breaker, as shown in `/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub open_until: Option<DateTime<Utc>>,
+}
+
+/// The error calls fail with while the circuit is open, so callers can tell
+/// a call that was never made from one that failed.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub consecutive_failures: u32,
+    /// When the next trial call will be let through.
+    pub open_until: DateTime<Utc>,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LLM circuit breaker open after {} consecutive failures", self.consecutive_failures)
+    }
+}
+
+impl Error for CircuitOpen {}
+
+/// Stops calling an LLM endpoint that keeps failing, and lets a single call
+/// through once in a while to find out whether it recovered.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    status: Mutex<CircuitStatus>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            status: Mutex::new(CircuitStatus {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    /// Fails with `CircuitOpen` if calls are not allowed right now. Past
+    /// `open_until`, the first caller gets the trial call.
+    pub fn check(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut status = self.status.lock().unwrap();
+        match status.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open | CircuitState::HalfOpen if status.open_until.is_some_and(|until| Utc::now() >= until) => {
+                info!("LLM circuit breaker half-open, letting a trial call through");
+                status.state = CircuitState::HalfOpen;
+                status.open_until = Some(self.reopen_time());
+                Ok(())
+            }
+            CircuitState::Open | CircuitState::HalfOpen => Err(Box::new(CircuitOpen {
+                consecutive_failures: status.consecutive_failures,
+                open_until: status.open_until.unwrap_or_else(|| self.reopen_time()),
+            })),
+        }
+    }
+
+    fn reopen_time(&self) -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::seconds(self.config.open_secs as i64)
+    }
+
+    pub fn record_success(&self) {
+        let mut status = self.status.lock().unwrap();
+        if status.state != CircuitState::Closed {
+            info!("LLM circuit breaker closed");
+        }
+        status.state = CircuitState::Closed;
+        status.consecutive_failures = 0;
+        status.open_until = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.consecutive_failures += 1;
+        if status.state == CircuitState::HalfOpen || status.consecutive_failures >= self.config.failure_threshold {
+            let open_until = self.reopen_time();
+            if status.state != CircuitState::Open {
+                warn!("LLM circuit breaker open until {} after {} consecutive failures", open_until, status.consecutive_failures);
+            }
+            status.state = CircuitState::Open;
+            status.open_until = Some(open_until);
+        }
+    }
+
+    /// When the next trial call will be let through, if the circuit is not closed.
+    pub fn open_until(&self) -> Option<DateTime<Utc>> {
+        let status = self.status.lock().unwrap();
+        match status.state {
+            CircuitState::Closed => None,
+            CircuitState::Open | CircuitState::HalfOpen => status.open_until,
+        }
+    }
+
+    pub fn status(&self) -> CircuitStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failure_threshold: u32, open_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig { failure_threshold, open_secs })
+    }
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let breaker = breaker(3, 60);
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+        assert_eq!(breaker.status().state, CircuitState::Closed);
+
+        breaker.record_failure();
+        assert_eq!(breaker.status().state, CircuitState::Open);
+        let error = breaker.check().unwrap_err();
+        let open = error.downcast_ref::<CircuitOpen>().unwrap();
+        assert_eq!(Some(open.open_until), breaker.open_until());
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = breaker(2, 60);
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.status().state, CircuitState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 1);
+    }
+
+    #[test]
+    fn lets_a_single_trial_call_through_once_open_expires() {
+        let breaker = breaker(1, 60);
+        breaker.record_failure();
+        assert_eq!(breaker.status().state, CircuitState::Open);
+        assert!(breaker.check().is_err());
+
+        breaker.status.lock().unwrap().open_until = Some(Utc::now());
+        assert!(breaker.check().is_ok());
+        assert_eq!(breaker.status().state, CircuitState::HalfOpen);
+        assert!(breaker.open_until().is_some_and(|until| until > Utc::now()));
+        // The trial is in flight; everyone else waits for its outcome
+        assert!(breaker.check().is_err());
+    }
+
+    #[test]
+    fn successful_trial_closes_the_circuit() {
+        let breaker = breaker(1, 0);
+        breaker.record_failure();
+        breaker.check().unwrap();
+        breaker.record_success();
+        assert_eq!(breaker.status().state, CircuitState::Closed);
+        assert_eq!(breaker.open_until(), None);
+    }
+
+    #[test]
+    fn failed_trial_reopens_the_circuit() {
+        let breaker = breaker(5, 60);
+        for _ in 0..5 {
+            breaker.record_failure();
+        }
+        // Let the trial call through without waiting for open_secs
+        breaker.status.lock().unwrap().open_until = Some(Utc::now());
+        breaker.check().unwrap();
+        breaker.record_failure();
+        assert_eq!(breaker.status().state, CircuitState::Open);
+        assert!(breaker.check().is_err());
+    }
+}
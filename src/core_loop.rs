@@ -1,43 +1,12 @@
 use crate::subconscious::Subconscious;
-use crate::task_manager::TaskManager;
-use crate::llm_client::LLMClient;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tokio::time::{interval, Duration, Instant};
-use log::{info};
+use log::{debug, error, info};
 use colored::*;
-use crate::task_manager::TaskStatus;
 
-pub async fn status_check(task_manager: &TaskManager, llm_client: &LLMClient, start_time: std::time::Instant) {
-    let redis_status = task_manager.check_redis_connection().await.is_ok();
-    info!("Redis connection: {}", if redis_status { "OK" } else { "Failed" });
-
-    let llm_status = llm_client.check_llm_connection().await.is_ok();
-    info!("LLM connection: {}", if llm_status { "OK" } else { "Failed" });
-
-    let time_running = start_time.elapsed().as_secs();
-    let tasks = task_manager.get_tasks().await;
-    let ongoing_tasks: Vec<String> = tasks.into_iter().filter(|task| task.status == TaskStatus::Pending).map(|task| task.description.clone()).collect();
-
-    info!("Time running: {} seconds, Ongoing tasks: {:?}", time_running, ongoing_tasks);
-}
-
-pub async fn core_loop(subconscious: Arc<Mutex<Subconscious>>) {
-    let subconscious_for_interval = Arc::clone(&subconscious);
+pub async fn core_loop(subconscious: Arc<Subconscious>) {
     let subconscious_for_connection_check = Arc::clone(&subconscious);
 
-    // Start the routine task adder
-    tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(10));
-        loop {
-            interval.tick().await;
-            let subconscious = subconscious_for_interval.lock().await;
-            if let Err(e) = subconscious.add_routine_task().await {
-                eprintln!("Failed to add routine task: {}", e);
-            }
-        }
-    });
-
     // Start the connection checker and performance logger
     tokio::spawn(async move {
         let mut connection_check_interval = interval(Duration::from_secs(10));
@@ -45,7 +14,7 @@ pub async fn core_loop(subconscious: Arc<Mutex<Subconscious>>) {
         loop {
             connection_check_interval.tick().await;
 
-            let subconscious = subconscious_for_connection_check.lock().await;
+            let subconscious = &subconscious_for_connection_check;
 
             info!("Checking Redis connection...");
             match subconscious.task_manager.check_redis_connection().await {
@@ -59,6 +28,23 @@ pub async fn core_loop(subconscious: Arc<Mutex<Subconscious>>) {
                 Err(e) => eprintln!("Failed to check LLM connection: {}", e),
             }
 
+            // Pick up edited prompt templates
+            if let Err(e) = subconscious.templates.reload() {
+                eprintln!("Failed to reload prompt templates: {}", e);
+            }
+
+            match subconscious.task_manager.reclaim_expired_tasks().await {
+                Ok(0) => {}
+                Ok(count) => info!("Reclaimed {} tasks with expired leases", count),
+                Err(e) => eprintln!("Failed to reclaim expired tasks: {}", e),
+            }
+
+            match subconscious.task_manager.prune_expired_tasks().await {
+                Ok(0) => {}
+                Ok(count) => debug!("Pruned {} expired finished tasks", count),
+                Err(e) => eprintln!("Failed to prune expired tasks: {}", e),
+            }
+
             let elapsed = start_time.elapsed().as_secs();
             let iterations_per_second = elapsed as f64 / 10.0;
 
@@ -66,19 +52,28 @@ pub async fn core_loop(subconscious: Arc<Mutex<Subconscious>>) {
             let ongoing_task_descriptions: Vec<String> = ongoing_tasks.iter().map(|task| task.description.clone()).collect();
 
             println!(
-                "{}",
-                format!(
-                    "Time running: {} seconds, Iterations per second: {:.2}, Ongoing tasks: {:?}",
-                    elapsed.to_string().purple(),
-                    iterations_per_second.to_string().blue(),
-                    ongoing_task_descriptions
-                )
+                "Time running: {} seconds, Iterations per second: {:.2}, Ongoing tasks: {:?}",
+                elapsed.to_string().purple(),
+                iterations_per_second.to_string().blue(),
+                ongoing_task_descriptions
             );
         }
     });
 
-    loop {
-        let mut subconscious = subconscious.lock().await;
-        subconscious.process_tasks().await;
+    // Start the workers, each claiming and executing tasks on its own
+    let mut workers = Vec::new();
+    for worker in 0..subconscious.workers {
+        let subconscious = Arc::clone(&subconscious);
+        workers.push(tokio::spawn(async move {
+            info!("Worker {} started", worker);
+            loop {
+                subconscious.process_tasks().await;
+            }
+        }));
+    }
+    for worker in workers {
+        if let Err(e) = worker.await {
+            error!("Worker stopped: {:?}", e);
+        }
     }
 }
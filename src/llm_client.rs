@@ -1,130 +1,839 @@
 use colored::*;
-use reqwest::Client;
+use futures::stream::{self, Stream, StreamExt};
+use jsonschema::JSONSchema;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::error::Error;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::future::Future;
+use std::time::Instant;
+use chrono::{DateTime, Utc};
+use crate::backends::{is_endpoint_failure, EndpointStatus, LlmBackend, ModelInfo};
+use crate::cache::ResponseCache;
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitStatus};
+use crate::prompt::{task_lines, ContextSection, PromptBuilder, DEFAULT_CONTEXT_LENGTH, DEFAULT_REPLY_TOKENS, MEMORY_PRIORITY, TASKS_PRIORITY};
+use crate::tools::{FunctionCall, ToolCall, ToolDefinition};
+use crate::usage::{UsageRecord, UsageStore};
 use crate::task_manager::Task; // Import Task
 
-#[derive(Debug, Serialize)]
-struct LLMInput {
-    model: String,
-    prompt: String,
-    stream: bool,
+/// Author of a chat message.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// Result of a tool call.
+    Tool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LLMOutput {
-    response: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    #[serde(default)]
+    pub content: String,
+    /// Tools the assistant called in this message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Tool whose result a `Tool` message holds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_name: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-struct ModelInfoRequest {
-    name: String,
+impl ChatMessage {
+    fn new(role: Role, content: &str) -> Self {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+            tool_calls: Vec::new(),
+            tool_name: None,
+        }
+    }
+
+    pub fn system(content: &str) -> Self {
+        ChatMessage::new(Role::System, content)
+    }
+
+    pub fn user(content: &str) -> Self {
+        ChatMessage::new(Role::User, content)
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        ChatMessage::new(Role::Assistant, content)
+    }
+
+    pub fn tool(tool_name: &str, content: &str) -> Self {
+        ChatMessage {
+            tool_name: Some(tool_name.to_string()),
+            ..ChatMessage::new(Role::Tool, content)
+        }
+    }
+
+    pub fn with_tool_calls(mut self, tool_calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ModelInfoResponse {
-    modelfile: String,
-    parameters: String,
-    template: String,
-    details: ModelDetails,
+/// How tools are offered to the model, set with `llm.tool_mode` in `config.toml`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolMode {
+    /// Native tool calling when the backend supports it and the model accepts
+    /// it, the prompt otherwise.
+    #[default]
+    Auto,
+    Native,
+    /// Tools are described in the prompt and called by replying with JSON.
+    Prompt,
+}
+
+/// Models to run tasks on, read from `[llm.routing]` in `config.toml`. Each
+/// route is a fallback chain: the task runs on the first of its models that is
+/// installed on the server.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ModelRoutes {
+    pub actions: HashMap<String, Vec<String>>,
+    pub tags: HashMap<String, Vec<String>>,
+}
+
+impl ModelRoutes {
+    /// The route of the first of the task's tags that has one, else the route of its action.
+    fn chain_for(&self, task: &Task) -> Option<&Vec<String>> {
+        task.tags.iter()
+            .find_map(|tag| self.tags.get(tag))
+            .or_else(|| self.actions.get(&task.action))
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ModelDetails {
-    format: String,
-    family: String,
-    families: Vec<String>,
-    parameter_size: String,
-    quantization_level: String,
+/// Whether `model` is among the `installed` models. Ollama lists untagged
+/// models with an explicit ":latest".
+fn is_installed(installed: &[String], model: &str) -> bool {
+    installed.iter().any(|name| name == model || *name == format!("{}:latest", model))
 }
 
+/// Whether `error` is the server answering that the requested model doesn't
+/// exist, as Ollama and OpenAI-compatible servers do with a 404.
+fn is_model_not_found(error: &(dyn Error + Send + Sync + 'static)) -> bool {
+    let message = error.to_string();
+    message.contains("404 Not Found") && message.to_lowercase().contains("model")
+}
+
+/// Sampling and length settings sent with a request. Unset fields are left to
+/// the server's defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct GenerationOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Context window size, in tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u64>,
+    /// Maximum number of tokens to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl GenerationOptions {
+    /// These options with every field set in `overrides` replaced.
+    pub fn merge(&self, overrides: &GenerationOptions) -> GenerationOptions {
+        GenerationOptions {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            num_ctx: overrides.num_ctx.or(self.num_ctx),
+            num_predict: overrides.num_predict.or(self.num_predict),
+            stop: overrides.stop.clone().or_else(|| self.stop.clone()),
+            seed: overrides.seed.or(self.seed),
+        }
+    }
+}
+
+/// Tokens of a streamed LLM response, in the order they are generated.
+pub type TokenStream = Pin<Box<dyn Stream<Item = Result<String, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// One item of a reply streamed by a backend.
+#[derive(Debug)]
+pub enum StreamChunk {
+    Token(String),
+    /// The token counts and durations reported by the server once the reply
+    /// is complete. Its `text` is empty.
+    Done(LLMResponse),
+}
+
+/// Reply of a backend, streamed as tokens followed by the usage reported for it.
+pub type ChunkStream = Pin<Box<dyn Stream<Item = Result<StreamChunk, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// Text generated by the LLM together with the model and token counts reported for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LLMResponse {
+    pub text: String,
+    pub model: String,
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    /// Durations reported by the server, if it reports them.
+    pub total_duration_ms: Option<u64>,
+    pub load_duration_ms: Option<u64>,
+    pub eval_duration_ms: Option<u64>,
+    /// Tools the model asked to call instead of answering.
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Key of the persona used when an action has none of its own.
+const DEFAULT_PERSONA: &str = "default";
+
+/// Default number of times an invalid JSON reply is sent back for correction.
+const DEFAULT_JSON_REPAIR_ATTEMPTS: u32 = 2;
+
 #[derive(Clone, Debug)]
 pub struct LLMClient {
-    url: String,
-    client: Client,
-    model: String,
+    backend: Arc<dyn LlmBackend>,
+    /// Backends of the actions with their own timeouts, in place of `backend`.
+    action_backends: HashMap<String, Arc<dyn LlmBackend>>,
+    /// Shared by every clone, so the workers and the API see the same failures.
+    breaker: Arc<CircuitBreaker>,
+    /// Breaker of the embedding calls, which may go to another model and
+    /// mustn't hold back the chat calls.
+    embedding_breaker: Arc<CircuitBreaker>,
+    /// Shared by every clone, so a model change reaches the API and the workers alike.
+    model: Arc<RwLock<String>>,
+    /// System prompts keyed by action name, plus `DEFAULT_PERSONA`.
+    personas: HashMap<String, String>,
+    options: GenerationOptions,
+    /// Per-action overrides of `options`.
+    action_options: HashMap<String, GenerationOptions>,
+    routes: ModelRoutes,
+    /// Model computing the embeddings, the current model if unset.
+    embedding_model: Option<String>,
+    /// Models installed on the server as of the last connection check, `None` before the first one.
+    installed_models: Arc<RwLock<Option<Vec<String>>>>,
+    /// Model metadata reported by the server, for the context windows.
+    model_infos: Arc<RwLock<HashMap<String, ModelInfo>>>,
+    usage: Option<UsageStore>,
+    cache: Option<ResponseCache>,
+    /// How many times `chat_json` re-prompts the model with the validation errors.
+    json_repair_attempts: u32,
+    tool_mode: ToolMode,
 }
 
 impl LLMClient {
-    pub fn new(url: &str, model: &str) -> Self {
+    pub fn new(backend: Arc<dyn LlmBackend>, model: &str) -> Self {
         LLMClient {
-            url: url.to_string(),
-            client: Client::new(),
-            model: model.to_string(),
+            backend,
+            action_backends: HashMap::new(),
+            breaker: Arc::new(CircuitBreaker::new(CircuitBreakerConfig::default())),
+            embedding_breaker: Arc::new(CircuitBreaker::new(CircuitBreakerConfig::default())),
+            model: Arc::new(RwLock::new(model.to_string())),
+            personas: HashMap::new(),
+            options: GenerationOptions::default(),
+            action_options: HashMap::new(),
+            routes: ModelRoutes::default(),
+            embedding_model: None,
+            installed_models: Arc::new(RwLock::new(None)),
+            model_infos: Arc::new(RwLock::new(HashMap::new())),
+            usage: None,
+            cache: None,
+            json_repair_attempts: DEFAULT_JSON_REPAIR_ATTEMPTS,
+            tool_mode: ToolMode::default(),
+        }
+    }
+
+    pub fn with_personas(mut self, personas: HashMap<String, String>) -> Self {
+        self.personas = personas;
+        self
+    }
+
+    pub fn with_options(mut self, options: GenerationOptions, action_options: HashMap<String, GenerationOptions>) -> Self {
+        self.options = options;
+        self.action_options = action_options;
+        self
+    }
+
+    /// Runs tasks on the models of their route instead of the current model.
+    pub fn with_routes(mut self, routes: ModelRoutes) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Computes embeddings with `model` instead of the current model.
+    pub fn with_embedding_model(mut self, model: Option<String>) -> Self {
+        self.embedding_model = model;
+        self
+    }
+
+    /// Records every call in `usage`.
+    pub fn with_usage(mut self, usage: UsageStore) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// Serves the calls of the actions opted in to `cache` from it.
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn with_json_repair_attempts(mut self, json_repair_attempts: u32) -> Self {
+        self.json_repair_attempts = json_repair_attempts;
+        self
+    }
+
+    pub fn with_tool_mode(mut self, tool_mode: ToolMode) -> Self {
+        self.tool_mode = tool_mode;
+        self
+    }
+
+    /// Backends used for the tasks of the given actions instead of the default one.
+    pub fn with_action_backends(mut self, action_backends: HashMap<String, Arc<dyn LlmBackend>>) -> Self {
+        self.action_backends = action_backends;
+        self
+    }
+
+    /// Settings of both the chat and the embedding circuit breakers.
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker = Arc::new(CircuitBreaker::new(config.clone()));
+        self.embedding_breaker = Arc::new(CircuitBreaker::new(config));
+        self
+    }
+
+    fn backend_for(&self, task: Option<&Task>) -> &Arc<dyn LlmBackend> {
+        task.and_then(|task| self.action_backends.get(&task.action))
+            .unwrap_or(&self.backend)
+    }
+
+    /// Runs `call` unless `breaker` is open, and records its outcome. Only an
+    /// endpoint that couldn't be reached or timed out counts as a failure: an
+    /// error the server answered with shows it is up.
+    async fn guarded<T>(breaker: &CircuitBreaker, call: impl Future<Output = Result<T, Box<dyn Error + Send + Sync>>>) -> Result<T, Box<dyn Error + Send + Sync>> {
+        breaker.check()?;
+        let result = call.await;
+        match &result {
+            Err(e) if is_endpoint_failure(e.as_ref()) => breaker.record_failure(),
+            _ => breaker.record_success(),
+        }
+        result
+    }
+
+    /// When LLM calls will be let through again, if the circuit breaker is open.
+    pub fn circuit_open_until(&self) -> Option<DateTime<Utc>> {
+        self.breaker.open_until().filter(|until| *until > Utc::now())
+    }
+
+    pub fn circuit_status(&self) -> CircuitStatus {
+        self.breaker.status()
+    }
+
+    pub fn embedding_circuit_status(&self) -> CircuitStatus {
+        self.embedding_breaker.status()
+    }
+
+    async fn record_usage(&self, record: UsageRecord) {
+        debug!("LLM call by {}: {} prompt tokens, {} completion tokens in {} ms ({:.1} tokens/s)",
+            record.action, record.prompt_tokens.unwrap_or(0), record.completion_tokens.unwrap_or(0),
+            record.total_duration_ms, record.tokens_per_sec().unwrap_or(0.0));
+        if let Some(usage) = &self.usage {
+            usage.record(&record).await;
+        }
+    }
+
+    /// Generation options for `task`: the global options, overridden by the
+    /// ones of the task's action, overridden by the task's own.
+    pub fn options_for(&self, task: Option<&Task>) -> GenerationOptions {
+        let mut options = self.options.clone();
+        if let Some(task) = task {
+            if let Some(action_options) = self.action_options.get(&task.action) {
+                options = options.merge(action_options);
+            }
+            if let Some(task_options) = &task.options {
+                options = options.merge(task_options);
+            }
         }
+        options
+    }
+
+    /// System prompt for `action`, falling back to the default persona.
+    pub fn system_prompt(&self, action: Option<&str>) -> Option<&str> {
+        action.and_then(|action| self.personas.get(action))
+            .or_else(|| self.personas.get(DEFAULT_PERSONA))
+            .map(|persona| persona.as_str())
     }
 
     pub async fn check_llm_connection(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let show_url = self.url.replace("generate", "show");
-        println!("Checking LLM connection to: {}", &show_url);
+        let model = self.model();
+        println!("Checking {} LLM connection for model: {}", self.backend.name(), &model);
+
+        let model_info = self.backend.health_check(&model).await?;
+        if let Err(e) = self.list_models().await {
+            warn!("Failed to list the installed models: {}", e);
+        }
+        println!("Model Information:");
+        println!("Name: {}", model_info.name.yellow());
+        println!("Format: {}", model_info.format.as_deref().unwrap_or("unknown"));
+        println!("Parameter Size: {}", model_info.parameter_size.as_deref().unwrap_or("unknown"));
+        println!("Quantization Level: {}", model_info.quantization_level.as_deref().unwrap_or("unknown"));
+        Ok(())
+    }
+
+    /// Health and load of the LLM servers, when there are several.
+    pub fn endpoints(&self) -> Vec<EndpointStatus> {
+        self.backend.endpoints()
+    }
+
+    pub fn model(&self) -> String {
+        self.model.read().unwrap().clone()
+    }
+
+    /// Models installed on the server, remembered for routing.
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, Box<dyn Error + Send + Sync>> {
+        let models = self.backend.list_models().await?;
+        *self.installed_models.write().unwrap() = Some(models.iter().map(|info| info.name.clone()).collect());
+        Ok(models)
+    }
 
-        let payload = ModelInfoRequest {
-            name: self.model.clone(),
+    /// Model to run `task` on: the first installed model of its route, or the
+    /// current model if it has none. Until the installed models are known,
+    /// the first model of the route is assumed to be.
+    pub fn model_for(&self, task: Option<&Task>) -> String {
+        let Some(task) = task else {
+            return self.model();
         };
+        let Some(chain) = self.routes.chain_for(task) else {
+            return self.model();
+        };
+        let installed = self.installed_models.read().unwrap();
+        let routed = chain.iter().find(|model| match installed.as_deref() {
+            Some(installed) => is_installed(installed, model),
+            None => true,
+        });
+        match routed {
+            Some(model) => model.clone(),
+            None => {
+                let model = self.model();
+                warn!("None of the models routed to task {} is installed, using {}", task.id, model);
+                model
+            }
+        }
+    }
 
-        let response = self.client.post(&show_url)
-            .json(&payload)
-            .send()
-            .await?;
+    /// Models to try `task` on, in order: `model_for`, the other installed
+    /// models of its route, then the current model.
+    fn models_for(&self, task: Option<&Task>) -> Vec<String> {
+        let mut models = vec![self.model_for(task)];
+        if let Some(chain) = task.and_then(|task| self.routes.chain_for(task)) {
+            let installed = self.installed_models.read().unwrap();
+            for model in chain {
+                if !models.contains(model) && installed.as_deref().is_none_or(|installed| is_installed(installed, model)) {
+                    models.push(model.clone());
+                }
+            }
+        }
+        let current = self.model();
+        if !models.contains(&current) {
+            models.push(current);
+        }
+        models
+    }
 
-        if response.status().is_success() {
-            let model_info = response.json::<ModelInfoResponse>().await?;
-            println!("Model Information:");
-            println!("Name: {}", self.model.yellow());
-            println!("Format: {}", model_info.details.format);
-            println!("Parameter Size: {}", model_info.details.parameter_size);
-            println!("Quantization Level: {}", model_info.details.quantization_level);
-            Ok(())
-        } else {
-            Err(format!("LLM endpoint returned status: {} - {}", response.status(), response.text().await?).into())
+    /// Forgets that `model` is installed, after the server said it isn't, so
+    /// routing skips it until the next connection check.
+    fn forget_installed(&self, model: &str) {
+        if let Some(installed) = self.installed_models.write().unwrap().as_mut() {
+            installed.retain(|name| name != model && *name != format!("{}:latest", model));
         }
     }
 
-    pub fn change_model(&mut self, model: &str) {
-        self.model = model.to_string();
+    /// Switches every clone of this client to `model`, once the server
+    /// confirms the model is installed.
+    pub async fn change_model(&self, model: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let models = self.list_models().await?;
+        let installed: Vec<String> = models.into_iter().map(|info| info.name).collect();
+        if !is_installed(&installed, model) {
+            return Err(format!("Model {} is not installed on the LLM server", model).into());
+        }
+        *self.model.write().unwrap() = model.to_string();
         println!("Changing model to {}", model);
+        Ok(())
+    }
+
+    /// Metadata of `model`, asked to the server once.
+    async fn cached_model_info(&self, model: &str) -> Option<ModelInfo> {
+        if let Some(info) = self.model_infos.read().unwrap().get(model) {
+            return Some(info.clone());
+        }
+        match self.backend.model_info(model).await {
+            Ok(info) => {
+                self.model_infos.write().unwrap().insert(model.to_string(), info.clone());
+                Some(info)
+            }
+            Err(e) => {
+                warn!("Failed to get the context length of {}: {}", model, e);
+                None
+            }
+        }
     }
 
-    pub async fn process_task(&self, task: &Task) -> Result<String, Box<dyn Error + Send + Sync>> {
-        let input = LLMInput {
-            model: self.model.clone(),
-            prompt: task.description.clone(),
-            stream: false,
+    /// Tokens a prompt for `task` may use: the context window the server runs
+    /// the model with, minus room for the reply. That is `num_ctx` when it is
+    /// set, as it is sent with the request, up to the model's context length;
+    /// otherwise the server's default window for the model.
+    pub async fn prompt_budget(&self, task: Option<&Task>) -> usize {
+        let options = self.options_for(task);
+        let info = self.cached_model_info(&self.model_for(task)).await;
+        let context_length = info.as_ref().and_then(|info| info.context_length);
+        let window = match options.num_ctx {
+            Some(num_ctx) => Some(context_length.map_or(num_ctx, |context_length| context_length.min(num_ctx))),
+            None => info.as_ref().and_then(|info| info.default_context_length).or(context_length),
         };
+        let window = window.map_or(DEFAULT_CONTEXT_LENGTH, |window| window as usize);
+        let reply = match options.num_predict {
+            Some(num_predict) if num_predict > 0 => num_predict as usize,
+            _ => DEFAULT_REPLY_TOKENS,
+        };
+        window.saturating_sub(reply)
+    }
 
-        let response = self.client.post(&self.url)
-            .json(&input)
-            .send()
-            .await?;
+    /// Sends `messages` with the generation options of `task`, and accounts
+    /// the call to it. Calls without a task are accounted to the API.
+    pub async fn chat(&self, messages: &[ChatMessage], task: Option<&Task>) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        let (response, cache_key) = self.chat_with_schema(messages, task, None, true).await?;
+        if let Some(key) = cache_key {
+            self.cache_reply(&key, &response).await;
+        }
+        Ok(response)
+    }
 
-        if response.status().is_success() {
-            let output = response.json::<LLMOutput>().await?;
-            Ok(output.response)
-        } else {
-            Err(format!("LLM processing failed: {} - {}", response.status(), response.text().await?).into())
+    /// Sends `messages` to the models of `task` in turn, moving on to the
+    /// next one while the server answers that the model doesn't exist. With
+    /// `cached`, the reply may come from the cache, and the cache key to store
+    /// a fresh reply under is returned too; storing it is left to the caller.
+    async fn chat_with_schema(&self, messages: &[ChatMessage], task: Option<&Task>, schema: Option<&Value>, cached: bool) -> Result<(LLMResponse, Option<String>), Box<dyn Error + Send + Sync>> {
+        let mut result = Err("No model to send the messages to".into());
+        for model in self.models_for(task) {
+            result = self.chat_on(&model, messages, task, schema, cached).await;
+            match &result {
+                Err(e) if is_model_not_found(e.as_ref()) => {
+                    warn!("Model {} not found on the LLM server: {}", model, e);
+                    self.forget_installed(&model);
+                }
+                _ => break,
+            }
         }
+        result
     }
 
-    pub async fn process_query(&self, query: &str, tasks: Vec<Task>) -> Result<String, Box<dyn Error + Send + Sync>> {
-        let task_descriptions: Vec<String> = tasks.into_iter().map(|task| task.description).collect();
-        let task_info = format!("Current tasks: {:?}", task_descriptions);
+    async fn chat_on(&self, model: &str, messages: &[ChatMessage], task: Option<&Task>, schema: Option<&Value>, cached: bool) -> Result<(LLMResponse, Option<String>), Box<dyn Error + Send + Sync>> {
+        let options = self.options_for(task);
+        let backend = self.backend_for(task);
+        let cache_key = self.cache.as_ref()
+            .filter(|cache| cached && cache.caches(task))
+            .map(|_| ResponseCache::key(backend.name(), model, messages, &options, schema));
+        if let (Some(cache), Some(key)) = (&self.cache, &cache_key) {
+            if let Some(response) = cache.get(key).await {
+                debug!("Serving LLM call for {} from the cache", task.map_or("the API", |task| task.action.as_str()));
+                return Ok((response, None));
+            }
+        }
 
-        let input = LLMInput {
-            model: self.model.clone(),
-            prompt: format!("{}\n{}", task_info, query),
-            stream: false,
-        };
+        let started = Instant::now();
+        let response = Self::guarded(&self.breaker, backend.chat(model, messages, &options, schema)).await?;
+        self.record_usage(UsageRecord::new(model, task, started.elapsed()).with_response(&response)).await;
+        Ok((response, cache_key))
+    }
+
+    async fn cache_reply(&self, key: &str, response: &LLMResponse) {
+        if let Some(cache) = &self.cache {
+            cache.put(key, response).await;
+        }
+    }
+
+    /// Like `chat`, but has the model reply with JSON following `schema`. A reply
+    /// that isn't valid is sent back with the validation errors, up to
+    /// `json_repair_attempts` times, before giving up. Only a valid reply is
+    /// cached, under the original request.
+    pub async fn chat_json(&self, messages: &[ChatMessage], task: Option<&Task>, schema: &Value) -> Result<(LLMResponse, Value), Box<dyn Error + Send + Sync>> {
+        let validator = JSONSchema::compile(schema).map_err(|e| format!("Invalid JSON schema: {}", e))?;
+        let mut messages = messages.to_vec();
+        messages.push(ChatMessage::user(&format!("Reply only with JSON following this JSON schema:\n{}", schema)));
+
+        let mut repairs = 0;
+        let mut cache_key = None;
+        loop {
+            let (response, key) = self.chat_with_schema(&messages, task, Some(schema), true).await?;
+            if repairs == 0 {
+                cache_key = key;
+            }
+            let problems: Vec<String> = match serde_json::from_str::<Value>(&response.text) {
+                Ok(value) => {
+                    let problems = match validator.validate(&value) {
+                        Ok(()) => Vec::new(),
+                        Err(errors) => errors.map(|e| format!("{} (at \"{}\")", e, e.instance_path)).collect(),
+                    };
+                    if problems.is_empty() {
+                        if let Some(key) = &cache_key {
+                            self.cache_reply(key, &response).await;
+                        }
+                        return Ok((response, value));
+                    }
+                    problems
+                }
+                Err(e) => vec![format!("Not valid JSON: {}", e)],
+            };
 
-        let response = self.client.post(&self.url)
-            .json(&input)
-            .send()
-            .await?;
+            if repairs >= self.json_repair_attempts {
+                return Err(format!("LLM reply doesn't follow the JSON schema after {} repairs: {}", repairs, problems.join("; ")).into());
+            }
+            repairs += 1;
+            warn!("LLM reply doesn't follow the JSON schema, asking for repair {}: {}", repairs, problems.join("; "));
+            messages.push(ChatMessage::assistant(&response.text));
+            messages.push(ChatMessage::user(&format!(
+                "Your reply doesn't follow the JSON schema:\n{}\nReply again with only the corrected JSON.",
+                problems.join("\n")
+            )));
+        }
+    }
+
+    /// One model turn offering `tools`. The calls the model makes are in the
+    /// response's `tool_calls`, whether it made them natively or, in prompt
+    /// mode, by replying with a JSON tool call.
+    pub async fn chat_tools(&self, messages: &[ChatMessage], task: Option<&Task>, tools: &[ToolDefinition]) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        let native = match self.tool_mode {
+            ToolMode::Native => true,
+            ToolMode::Prompt => false,
+            ToolMode::Auto => self.backend_for(task).supports_tools(),
+        };
+        if native {
+            let mut not_found = None;
+            for model in self.models_for(task) {
+                let started = Instant::now();
+                self.breaker.check()?;
+                match self.backend_for(task).chat_with_tools(&model, messages, &self.options_for(task), tools).await {
+                    Ok(response) => {
+                        self.breaker.record_success();
+                        self.record_usage(UsageRecord::new(&model, task, started.elapsed()).with_response(&response)).await;
+                        return Ok(response);
+                    }
+                    // Ollama rejects tools for models whose template doesn't support them
+                    Err(e) if self.tool_mode == ToolMode::Auto && e.to_string().contains("does not support tools") => {
+                        // The server answered, so this doesn't count against the circuit breaker
+                        self.breaker.record_success();
+                        warn!("Model {} doesn't support tools, describing them in the prompt", model);
+                        not_found = None;
+                        break;
+                    }
+                    Err(e) if is_model_not_found(e.as_ref()) => {
+                        self.breaker.record_success();
+                        warn!("Model {} not found on the LLM server: {}", model, e);
+                        self.forget_installed(&model);
+                        not_found = Some(e);
+                    }
+                    Err(e) => {
+                        if is_endpoint_failure(e.as_ref()) {
+                            self.breaker.record_failure();
+                        } else {
+                            self.breaker.record_success();
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            if let Some(e) = not_found {
+                return Err(e);
+            }
+        }
 
-        if response.status().is_success() {
-            let output = response.json::<LLMOutput>().await?;
-            Ok(output.response)
-        } else {
-            Err(format!("LLM processing failed: {} - {}", response.status(), response.text().await?).into())
+        // Never cached: replaying a tool call would run its side effects again
+        let (mut response, _) = self.chat_with_schema(&prompt_tool_messages(messages, tools), task, None, false).await?;
+        if let Some(call) = parse_prompt_tool_call(&response.text, tools) {
+            response.tool_calls.push(call);
         }
+        Ok(response)
+    }
+
+    /// Embedding vectors of `input`, computed by the embedding model. The call
+    /// is accounted to `task`, or to the API without one.
+    pub async fn embed(&self, input: &[String], task: Option<&Task>) -> Result<Vec<Vec<f32>>, Box<dyn Error + Send + Sync>> {
+        let model = self.embedding_model.clone().unwrap_or_else(|| self.model());
+        let started = Instant::now();
+        let embeddings = Self::guarded(&self.embedding_breaker, self.backend.embed(&model, input)).await?;
+        self.record_usage(UsageRecord::new(&model, task, started.elapsed())).await;
+        Ok(embeddings)
+    }
+
+    /// Like `chat`, but yields the reply token by token as the model generates it.
+    /// The call is accounted once the stream ends, with the token counts and
+    /// durations the server reported, or one completion token per streamed
+    /// chunk if it reported none.
+    pub async fn chat_stream(&self, messages: &[ChatMessage], task: Option<&Task>) -> Result<TokenStream, Box<dyn Error + Send + Sync>> {
+        let model = self.model_for(task);
+        let started = Instant::now();
+        let chunks = Self::guarded(&self.breaker, self.backend_for(task).chat_stream(&model, messages, &self.options_for(task))).await?;
+
+        let tokens_seen = Arc::new(AtomicU64::new(0));
+        let reported = Arc::new(Mutex::new(None));
+        let (counter, slot) = (tokens_seen.clone(), reported.clone());
+        let tokens = chunks.filter_map(move |chunk| {
+            let token = match chunk {
+                Ok(StreamChunk::Token(token)) => {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Some(Ok(token))
+                }
+                Ok(StreamChunk::Done(response)) => {
+                    *slot.lock().unwrap() = Some(response);
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            };
+            async move { token }
+        });
+        let client = self.clone();
+        let mut record = UsageRecord::new(&model, task, started.elapsed());
+        let accounting = stream::once(async move {
+            record.total_duration_ms = started.elapsed().as_millis() as u64;
+            let response = reported.lock().unwrap().take();
+            let record = match response {
+                Some(response) => record.with_response(&response),
+                None => {
+                    record.completion_tokens = Some(tokens_seen.load(Ordering::Relaxed));
+                    record
+                }
+            };
+            client.record_usage(record).await;
+            None
+        }).filter_map(|token| async move { token });
+        Ok(Box::pin(tokens.chain(accounting)))
+    }
+
+    /// Messages answering a user query, continuing the conversation in
+    /// `history`, with the related `memories` and the current tasks given to
+    /// the model as context. The oldest turns and the least important tasks
+    /// are left out to fit the model's context window.
+    pub async fn query_messages(&self, query: &str, history: &[ChatMessage], tasks: Vec<Task>, memories: Vec<String>) -> Vec<ChatMessage> {
+        let (messages, report) = PromptBuilder::new(self.prompt_budget(None).await, query)
+            .with_system(self.system_prompt(None))
+            .with_section(ContextSection::new("Related memories", MEMORY_PRIORITY, memories))
+            .with_section(ContextSection::new("Current tasks", TASKS_PRIORITY, task_lines(&tasks)))
+            .with_history(history)
+            .build();
+        report.log("query");
+        messages
+    }
+
+    pub async fn process_query_stream(&self, query: &str, history: &[ChatMessage], tasks: Vec<Task>, memories: Vec<String>) -> Result<TokenStream, Box<dyn Error + Send + Sync>> {
+        self.chat_stream(&self.query_messages(query, history, tasks, memories).await, None).await
+    }
+}
+
+/// `messages` for a model without native tool support: the tools are described
+/// in a system message, and earlier tool calls and results become plain turns.
+fn prompt_tool_messages(messages: &[ChatMessage], tools: &[ToolDefinition]) -> Vec<ChatMessage> {
+    let descriptions: Vec<String> = tools.iter()
+        .map(|tool| format!("- {}: {} Arguments (JSON schema): {}", tool.name, tool.description, tool.parameters))
+        .collect();
+    let instructions = format!(
+        "You can call these tools:\n{}\n\nTo call a tool, reply with only a JSON object like \
+         {{\"tool\": \"<name>\", \"arguments\": {{...}}}} and nothing else. The result will be sent back to you. \
+         Once you know enough, reply normally.",
+        descriptions.join("\n")
+    );
+
+    let mut prompt_messages: Vec<ChatMessage> = messages.iter()
+        .map(|message| match message.role {
+            Role::Tool => ChatMessage::user(&format!(
+                "Result of tool {}:\n{}", message.tool_name.as_deref().unwrap_or("call"), message.content)),
+            _ => ChatMessage::new(message.role.clone(), &message.content),
+        })
+        .collect();
+    let first_turn = prompt_messages.iter().position(|message| message.role != Role::System).unwrap_or(prompt_messages.len());
+    prompt_messages.insert(first_turn, ChatMessage::system(&instructions));
+    prompt_messages
+}
+
+/// The tool call in a prompt mode reply, if the reply is one.
+fn parse_prompt_tool_call(text: &str, tools: &[ToolDefinition]) -> Option<ToolCall> {
+    let text = text.trim();
+    let text = text.strip_prefix("```json").or_else(|| text.strip_prefix("```")).unwrap_or(text);
+    let text = text.strip_suffix("```").unwrap_or(text).trim();
+
+    let call: Value = serde_json::from_str(text).ok()?;
+    let name = call.get("tool")?.as_str()?;
+    if !tools.iter().any(|tool| tool.name == name) {
+        return None;
+    }
+    Some(ToolCall {
+        function: FunctionCall {
+            name: name.to_string(),
+            arguments: call.get("arguments").cloned().unwrap_or_else(|| Value::Object(Default::default())),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tools() -> Vec<ToolDefinition> {
+        vec![ToolDefinition {
+            name: "get_task".to_string(),
+            description: "Gets a task".to_string(),
+            parameters: json!({ "type": "object" }),
+        }]
+    }
+
+    #[test]
+    fn merge_overrides_only_the_fields_set() {
+        let global = GenerationOptions {
+            temperature: Some(0.7),
+            top_p: Some(0.9),
+            num_ctx: Some(8192),
+            stop: Some(vec!["END".to_string()]),
+            ..GenerationOptions::default()
+        };
+        let action = GenerationOptions {
+            temperature: Some(0.2),
+            num_predict: Some(256),
+            ..GenerationOptions::default()
+        };
+        assert_eq!(global.merge(&action), GenerationOptions {
+            temperature: Some(0.2),
+            top_p: Some(0.9),
+            num_ctx: Some(8192),
+            num_predict: Some(256),
+            stop: Some(vec!["END".to_string()]),
+            seed: None,
+        });
+        assert_eq!(global.merge(&GenerationOptions::default()), global);
+    }
+
+    #[test]
+    fn parses_a_prompt_tool_call() {
+        let call = parse_prompt_tool_call(r#"{"tool": "get_task", "arguments": {"id": "42"}}"#, &tools()).unwrap();
+        assert_eq!(call.function.name, "get_task");
+        assert_eq!(call.function.arguments, json!({ "id": "42" }));
+    }
+
+    #[test]
+    fn parses_a_fenced_prompt_tool_call_without_arguments() {
+        let call = parse_prompt_tool_call("```json\n{\"tool\": \"get_task\"}\n```", &tools()).unwrap();
+        assert_eq!(call.function.name, "get_task");
+        assert_eq!(call.function.arguments, json!({}));
+    }
+
+    #[test]
+    fn ignores_replies_that_are_not_a_known_tool_call() {
+        assert!(parse_prompt_tool_call("The task is done.", &tools()).is_none());
+        assert!(parse_prompt_tool_call(r#"{"answer": "done"}"#, &tools()).is_none());
+        assert!(parse_prompt_tool_call(r#"{"tool": "delete_everything"}"#, &tools()).is_none());
     }
 }
@@ -1,25 +1,53 @@
 use tokio::main;
-use crate::task_manager::{Task, TaskManager, TaskStatus};
+use crate::task_manager::{Recurrence, RetryPolicy, Task, TaskManager, ROUTINE_PRIORITY};
 use crate::core_loop::core_loop;
-use crate::subconscious::Subconscious;
-use crate::llm_client::LLMClient;
+use crate::subconscious::{ExecutorConfig, Subconscious};
+use crate::llm_client::{ChatMessage, GenerationOptions, LLMClient, ModelRoutes, ToolMode};
+use crate::actions::ActionRegistry;
+use crate::tools::{ToolContext, ToolRegistry, DEFAULT_MAX_TOOL_ROUNDS};
+use crate::results::ResultsConfig;
+use crate::backends::{BackendKind, EndpointConfig, LlmBackend, LlmTimeouts, PoolBackend};
+use crate::circuit_breaker::CircuitBreakerConfig;
+use crate::usage::UsageStore;
+use crate::templates::{TemplateConfig, TemplateLibrary};
+use crate::cache::{CacheConfig, ResponseCache};
+use crate::memory::{MemoryConfig, MemoryKind, MemoryStore};
+use crate::prompt::instruction_budget;
 use config::{Config};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use warp::Filter;
+use warp::sse::Event;
+use futures::stream::{self, StreamExt};
+use std::convert::Infallible;
 use tokio::sync::Mutex;
 use log::{info, debug, error};
 use std::fs::OpenOptions;
 use log::LevelFilter;
 use env_logger::{Builder, Target};
 use warp::reject::Reject;
-use warp::Reply;
 use serde::Deserialize;
+use serde_json::{json, Value};
 
+mod actions;
 mod task_manager;
 mod core_loop;
 mod subconscious;
 mod llm_client;
+mod results;
+mod backends;
+mod usage;
+mod tools;
+mod circuit_breaker;
+mod prompt;
+mod templates;
+mod cache;
+mod memory;
+
+/// Application log file, also read back by the log analysis actions.
+pub const LOG_FILE: &str = "subconscious_ai.log";
 
 #[derive(Debug)]
 struct CustomError;
@@ -29,6 +57,65 @@ impl Reject for CustomError {}
 #[derive(Deserialize)]
 struct QueryInput {
     query: String,
+    /// Previous turns of the conversation, oldest first.
+    #[serde(default)]
+    history: Vec<ChatMessage>,
+}
+
+/// Body of `/add_task`. Only the fields a client may choose; the status,
+/// attempts and timestamps are managed by the task manager.
+#[derive(Debug, Deserialize)]
+struct TaskInput {
+    description: String,
+    action: String,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    depends_on: Vec<String>,
+    #[serde(default)]
+    is_permanent: bool,
+    recurrence: Option<Recurrence>,
+    retry_policy: Option<RetryPolicy>,
+    options: Option<GenerationOptions>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl TaskInput {
+    fn into_task(self) -> Task {
+        let mut task = Task::new(&self.description, &self.action, self.is_permanent)
+            .with_priority(self.priority)
+            .with_dependencies(self.depends_on)
+            .with_tags(self.tags);
+        task.recurrence = self.recurrence;
+        task.retry_policy = self.retry_policy;
+        task.options = self.options;
+        task
+    }
+}
+
+#[derive(Deserialize)]
+struct UsageCallsQuery {
+    #[serde(default = "default_usage_calls")]
+    count: usize,
+}
+
+fn default_usage_calls() -> usize {
+    50
+}
+
+#[derive(Deserialize)]
+struct MemorySearchQuery {
+    query: String,
+    /// Number of memories returned, `memory.top_k` if unset.
+    k: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct TemplatePreviewQuery {
+    version: Option<u32>,
+    /// Task to render the template for, instead of a sample task of the template's action.
+    task_id: Option<String>,
 }
 
 #[main]
@@ -37,7 +124,7 @@ async fn main() {
     let file = OpenOptions::new()
         .append(true)
         .create(true)
-        .open("subconscious_ai.log")
+        .open(LOG_FILE)
         .unwrap();
     Builder::new()
         .target(Target::Pipe(Box::new(file)))
@@ -53,55 +140,114 @@ async fn main() {
         .unwrap();
 
     let redis_url = settings.get_string("redis.url").unwrap();
-    let llm_url = settings.get_string("llm.url").unwrap();
+    // A single llm.url is a pool of one endpoint
+    let endpoints = settings.get::<Vec<EndpointConfig>>("llm.endpoints")
+        .unwrap_or_else(|_| vec![EndpointConfig::new(&settings.get_string("llm.url").unwrap())]);
     let model_name = settings.get_string("llm.model").unwrap();
-    let llm_client = LLMClient::new(&llm_url, &model_name);
-    let task_manager = TaskManager::new(&redis_url);
+    let personas = settings.get::<HashMap<String, String>>("personas").unwrap_or_default();
+    let backend_kind = settings.get_string("llm.backend")
+        .map(|kind| kind.parse::<BackendKind>().unwrap())
+        .unwrap_or_default();
+    let api_key = settings.get_string("llm.api_key").ok();
+    let timeouts = settings.get::<LlmTimeouts>("llm.timeouts").unwrap_or_default();
+    let action_timeouts = settings.get::<HashMap<String, LlmTimeouts>>("llm.action_timeouts").unwrap_or_default();
+    let visibility_timeout = settings.get_int("tasks.visibility_timeout_secs").unwrap_or(300) as u64;
+    // A lease shorter than an LLM call would hand the task to a second worker while the call still runs
+    let longest_call = action_timeouts.values()
+        .filter_map(|overrides| timeouts.merge(overrides).total_secs)
+        .chain(timeouts.total_secs)
+        .max();
+    if let Some(longest_call) = longest_call.filter(|longest_call| *longest_call > visibility_timeout) {
+        panic!("tasks.visibility_timeout_secs ({}) is shorter than the longest LLM total_secs timeout ({})", visibility_timeout, longest_call);
+    }
+    let pool = PoolBackend::new(backend_kind, &endpoints, api_key, &timeouts);
+    // Actions with their own timeouts get their own HTTP clients
+    let action_backends: HashMap<String, Arc<dyn LlmBackend>> = action_timeouts
+        .into_iter()
+        .map(|(action, action_timeouts)| {
+            let backend: Arc<dyn LlmBackend> = Arc::new(pool.with_timeouts(&timeouts.merge(&action_timeouts)));
+            (action, backend)
+        })
+        .collect();
+    let backend: Arc<dyn LlmBackend> = Arc::new(pool);
+    let options = settings.get::<GenerationOptions>("llm.options").unwrap_or_default();
+    let action_options = settings.get::<HashMap<String, GenerationOptions>>("llm.action_options").unwrap_or_default();
+    let redis_client = redis::Client::open(redis_url.as_str()).expect("Invalid Redis URL");
+    let usage = UsageStore::new(redis_client.clone());
+    let cache = ResponseCache::new(redis_client.clone(), settings.get::<CacheConfig>("llm.cache").unwrap_or_default());
+    let llm_client = LLMClient::new(backend, &model_name)
+        .with_personas(personas)
+        .with_options(options, action_options)
+        .with_action_backends(action_backends)
+        .with_routes(settings.get::<ModelRoutes>("llm.routing").unwrap_or_default())
+        .with_embedding_model(settings.get_string("llm.embedding_model").ok())
+        .with_circuit_breaker(settings.get::<CircuitBreakerConfig>("llm.circuit_breaker").unwrap_or_default())
+        .with_usage(usage.clone())
+        .with_cache(cache.clone())
+        .with_json_repair_attempts(settings.get_int("llm.json_repair_attempts").unwrap_or(2) as u32)
+        .with_tool_mode(settings.get::<ToolMode>("llm.tool_mode").unwrap_or_default());
+    let memory = MemoryStore::new(redis_client, llm_client.clone(), settings.get::<MemoryConfig>("memory").unwrap_or_default());
+    let max_tool_rounds = settings.get_int("llm.max_tool_rounds").unwrap_or(DEFAULT_MAX_TOOL_ROUNDS as i64);
+    let tools = ToolRegistry::with_builtin_tools(ActionRegistry::with_builtin_actions().queueable_by_tools())
+        .with_max_rounds(max_tool_rounds as usize);
+    let retry_policy = settings.get::<RetryPolicy>("tasks.retry").unwrap_or_default();
+    if let Err(e) = retry_policy.validate() {
+        panic!("Invalid [tasks.retry]: {}", e);
+    }
+    let finished_ttl = settings.get_int("tasks.finished_ttl_secs").unwrap_or(86400);
+    let task_manager = TaskManager::new(&redis_url)
+        .with_visibility_timeout(Duration::from_secs(visibility_timeout))
+        .with_finished_ttl(Duration::from_secs(finished_ttl as u64))
+        .with_retry_policy(retry_policy)
+        .with_results_config(&settings.get::<ResultsConfig>("results").unwrap_or_default());
 
-    let subconscious = Arc::new(Mutex::new(Subconscious::new(task_manager.clone(), llm_client.clone())));
+    // Requeue tasks that were in flight when the previous run stopped
+    match task_manager.recover_in_flight_tasks().await {
+        Ok(0) => {}
+        Ok(count) => info!("Recovered {} in-flight tasks", count),
+        Err(e) => error!("Failed to recover in-flight tasks: {:?}", e),
+    }
+
+    let templates = TemplateLibrary::new(settings.get::<TemplateConfig>("prompts").unwrap_or_default());
+    let executor_config = settings.get::<ExecutorConfig>("executor").unwrap_or_default();
+    let mut subconscious = Subconscious::new(task_manager.clone(), llm_client.clone())
+        .with_tools(tools.clone())
+        .with_templates(templates.clone())
+        .with_executor_config(&executor_config);
+    if memory.enabled() {
+        subconscious = subconscious.with_memory(memory.clone());
+    }
+    let subconscious = Arc::new(subconscious);
 
     // Add the persistent task at startup
+    // Persistent tasks use their action as ID so restarts don't queue duplicates
     let persistent_tasks = vec![
-        Task {
-            description: "Self Health Check".to_string(),
-            action: "check_status".to_string(),
-            status: TaskStatus::Pending,
-            is_permanent: true,
-        },
-        Task {
-            description: "Read from Redis".to_string(),
-            action: "display_redis_data".to_string(),
-            status: TaskStatus::Pending,
-            is_permanent: true,
-        },
-        Task {
-            description: "Start LLM interaction".to_string(),
-            action: "start_llm_communications".to_string(),
-            status: TaskStatus::Pending,
-            is_permanent: true,
-        },
-        Task {
-            description: "Log Analysis".to_string(),
-            action: "comment_last_logs".to_string(),
-            status: TaskStatus::Pending,
-            is_permanent: true,
-        },
-        Task {
-            description: "Self analysis and new tasks".to_string(),
-            action: "take_improvement_actions".to_string(),
-            status: TaskStatus::Pending,
-            is_permanent: true,
-        },
-        Task {
-            description: "Write a detailed report of concepts and behaviors learned so far".to_string(),
-            action: "write_detailed_report".to_string(),
-            status: TaskStatus::Pending,
-            is_permanent: true,
-        }
+        Task::new("Routine check", "routine_check", true)
+            .with_priority(ROUTINE_PRIORITY)
+            .with_recurrence(Recurrence::Interval { seconds: 10 }),
+        Task::new("Self Health Check", "check_status", true)
+            .with_recurrence(Recurrence::Interval { seconds: 60 }),
+        Task::new("Read from Redis", "display_redis_data", true)
+            .with_recurrence(Recurrence::Interval { seconds: 300 }),
+        Task::new("Start LLM interaction", "start_llm_communications", true)
+            .with_recurrence(Recurrence::Cron { expression: "0 */10 * * * *".to_string() }),
+        Task::new("Log Analysis", "comment_last_logs", true)
+            .with_recurrence(Recurrence::Cron { expression: "0 5/10 * * * *".to_string() })
+            .with_dependencies(vec!["permanent:start_llm_communications".to_string()]),
+        Task::new("Self analysis and new tasks", "take_improvement_actions", true)
+            .with_recurrence(Recurrence::AfterTasks { count: 50 })
+            .with_dependencies(vec!["permanent:comment_last_logs".to_string()]),
+        Task::new("Write a detailed report of concepts and behaviors learned so far", "write_detailed_report", true)
+            .with_recurrence(Recurrence::Cron { expression: "0 0 * * * *".to_string() })
+            .with_dependencies(vec!["permanent:take_improvement_actions".to_string()]),
     ];
 
     for task in persistent_tasks {
-        if let Err(e) = task_manager.add_task(task.clone()).await {
+        let task_id = format!("permanent:{}", task.action);
+        let task = task.with_id(&task_id);
+        if task_manager.get_task(&task.id).await.is_some() {
+            debug!("Persistent task already scheduled: {}", task.id);
+        } else if let Err(e) = task_manager.add_task(task.clone()).await {
             error!("Failed to add persistent task: {:?}", e);
         } else {
             info!("Added persistent task: {:?}", task);
@@ -109,7 +255,7 @@ async fn main() {
     }
 
     // Shared state for API server
-    let state = Arc::new(Mutex::new(SomeSharedState::new(task_manager.clone(), llm_client.clone())));
+    let state = Arc::new(Mutex::new(SomeSharedState::new(task_manager.clone(), llm_client.clone(), tools, usage, templates, cache, memory)));
 
     // Clone the state for API thread
     let api_state = state.clone();
@@ -122,7 +268,7 @@ async fn main() {
 
             // Define API routes
             let hello_route = warp::path!("hello").map(|| "Hello from the API!");
-            let get_tasks = warp::path("tasks")
+            let get_tasks = warp::path!("tasks")
                 .and(warp::get())
                 .and(state_filter.clone())
                 .and_then(|state: Arc<Mutex<SomeSharedState>>| async move {
@@ -137,59 +283,167 @@ async fn main() {
                 .and(warp::post())
                 .and(warp::body::json())
                 .and(state_filter.clone())
-                .and_then(|task: Task, state: Arc<Mutex<SomeSharedState>>| async move {
-                    debug!("Received request to add task: {:?}", task);
-                    {
+                .and_then(|input: TaskInput, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to add task: {:?}", input);
+                    let task = input.into_task();
+                    let task_id = {
                         let state = state.lock().await;
                         debug!("Adding task to task manager: {:?}", task);
-                        if let Err(e) = state.task_manager.add_task(task.clone()).await {
-                            error!("Failed to add task via API: {:?}", e);
-                            return Err(warp::reject::custom(CustomError));
+                        match state.task_manager.add_task(task.clone()).await {
+                            Ok(task_id) => task_id,
+                            Err(e) => {
+                                error!("Failed to add task via API: {:?}", e);
+                                return Err(warp::reject::custom(CustomError));
+                            }
                         }
-                        debug!("Task added to task manager: {:?}", task);
+                    };
+                    info!("Task {} added via API: {:?}", task_id, task);
+                    Ok::<_, warp::Rejection>(warp::reply::json(&task_id))
+                });
+
+            let get_task = warp::path!("tasks" / String)
+                .and(warp::get())
+                .and(state_filter.clone())
+                .and_then(|task_id: String, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to get task: {}", task_id);
+                    let state = state.lock().await;
+                    match state.task_manager.get_task(&task_id).await {
+                        Some(task) => Ok::<_, warp::Rejection>(warp::reply::json(&task)),
+                        None => Err(warp::reject::not_found()),
                     }
-                    info!("Task added via API: {:?}", task);
-                    Ok::<_, warp::Rejection>(warp::reply::with_status("Task added", warp::http::StatusCode::OK))
                 });
 
-            let validate_task = warp::path("validate_task")
+            let validate_task = warp::path!("validate_task" / String)
                 .and(warp::post())
-                .and(warp::body::json())
                 .and(state_filter.clone())
-                .and_then(|task: Task, state: Arc<Mutex<SomeSharedState>>| async move {
-                    debug!("Received request to validate task: {:?}", task);
+                .and_then(|task_id: String, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to validate task: {}", task_id);
                     {
                         let state = state.lock().await;
-                        debug!("Validating task: {:?}", task);
-                        if let Err(e) = state.task_manager.update_task_status(&task, TaskStatus::Completed).await {
+                        debug!("Validating task: {}", task_id);
+                        if let Err(e) = state.task_manager.validate_task(&task_id).await {
                             error!("Failed to validate task via API: {:?}", e);
                             return Err(warp::reject::custom(CustomError));
                         }
-                        debug!("Task validated: {:?}", task);
+                        debug!("Task validated: {}", task_id);
                     }
-                    info!("Task validated via API: {:?}", task);
+                    info!("Task validated via API: {}", task_id);
                     Ok::<_, warp::Rejection>(warp::reply::with_status("Task validated", warp::http::StatusCode::OK))
                 });
 
+            let get_task_result = warp::path!("tasks" / String / "result")
+                .and(warp::get())
+                .and(state_filter.clone())
+                .and_then(|task_id: String, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to get result of task: {}", task_id);
+                    let state = state.lock().await;
+                    match state.task_manager.results.latest(&task_id).await {
+                        Ok(Some(result)) => Ok::<_, warp::Rejection>(warp::reply::json(&result)),
+                        Ok(None) => Err(warp::reject::not_found()),
+                        Err(e) => {
+                            error!("Failed to get result of task {}: {:?}", task_id, e);
+                            Err(warp::reject::custom(CustomError))
+                        }
+                    }
+                });
+
+            let get_task_history = warp::path!("tasks" / String / "history")
+                .and(warp::get())
+                .and(state_filter.clone())
+                .and_then(|task_id: String, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to get history of task: {}", task_id);
+                    let state = state.lock().await;
+                    match state.task_manager.results.history(&task_id).await {
+                        Ok(history) => Ok::<_, warp::Rejection>(warp::reply::json(&history)),
+                        Err(e) => {
+                            error!("Failed to get history of task {}: {:?}", task_id, e);
+                            Err(warp::reject::custom(CustomError))
+                        }
+                    }
+                });
+
+            let get_dead_letter = warp::path!("dead_letter")
+                .and(warp::get())
+                .and(state_filter.clone())
+                .and_then(|state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to get dead-lettered tasks");
+                    let state = state.lock().await;
+                    match state.task_manager.get_dead_letter_tasks().await {
+                        Ok(tasks) => Ok::<_, warp::Rejection>(warp::reply::json(&tasks)),
+                        Err(e) => {
+                            error!("Failed to get dead-lettered tasks: {:?}", e);
+                            Err(warp::reject::custom(CustomError))
+                        }
+                    }
+                });
+
+            let replay_dead_letter = warp::path!("dead_letter" / String / "replay")
+                .and(warp::post())
+                .and(state_filter.clone())
+                .and_then(|task_id: String, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to replay task: {}", task_id);
+                    let state = state.lock().await;
+                    if let Err(e) = state.task_manager.replay_dead_letter_task(&task_id).await {
+                        error!("Failed to replay task {}: {:?}", task_id, e);
+                        return Err(warp::reject::custom(CustomError));
+                    }
+                    info!("Task replayed via API: {}", task_id);
+                    Ok::<_, warp::Rejection>(warp::reply::with_status("Task replayed", warp::http::StatusCode::OK))
+                });
+
+            let get_models = warp::path!("models")
+                .and(warp::get())
+                .and(state_filter.clone())
+                .and_then(|state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to list models");
+                    let state = state.lock().await.clone();
+                    match state.llm_client.list_models().await {
+                        Ok(models) => Ok::<_, warp::Rejection>(warp::reply::json(&models)),
+                        Err(e) => {
+                            error!("Failed to list models: {:?}", e);
+                            Err(warp::reject::custom(CustomError))
+                        }
+                    }
+                });
+
             let change_model = warp::path!("change_model" / String)
                 .and(warp::post())
                 .and(state_filter.clone())
                 .and_then(|model: String, state: Arc<Mutex<SomeSharedState>>| async move {
-                    let mut state = state.lock().await;
+                    let state = state.lock().await.clone();
                     debug!("Changing model to: {}", model);
-                    state.llm_client.change_model(&model);
-                    Ok::<_, warp::Rejection>(warp::reply::json(&format!("Model changed to: {}", model)))
+                    match state.llm_client.change_model(&model).await {
+                        Ok(()) => {
+                            info!("Model changed via API: {}", model);
+                            Ok::<_, warp::Rejection>(warp::reply::with_status(
+                                warp::reply::json(&format!("Model changed to: {}", model)),
+                                warp::http::StatusCode::OK))
+                        }
+                        Err(e) => {
+                            error!("Failed to change model to {}: {:?}", model, e);
+                            Ok(warp::reply::with_status(warp::reply::json(&e.to_string()), warp::http::StatusCode::BAD_REQUEST))
+                        }
+                    }
                 });
 
-            let ask_llm = warp::path("ask_llm")
+            let ask_llm = warp::path!("ask_llm")
                 .and(warp::post())
                 .and(warp::body::json())
                 .and(state_filter.clone())
                 .and_then(|query: QueryInput, state: Arc<Mutex<SomeSharedState>>| async move {
-                    let state = state.lock().await;
+                    let state = state.lock().await.clone();
                     let tasks = state.task_manager.get_tasks().await;
-                    match state.llm_client.process_query(&query.query, tasks).await {
-                        Ok(response) => Ok::<_, warp::Rejection>(warp::reply::json(&response)),
+                    let memories = state.memory.related(&query.query, None).await;
+                    let messages = state.llm_client.query_messages(&query.query, &query.history, tasks, memories).await;
+                    let ctx = ToolContext {
+                        task_manager: &state.task_manager,
+                        llm_client: &state.llm_client,
+                    };
+                    match state.tools.run(messages, None, &ctx).await {
+                        Ok(response) => {
+                            state.memory.remember(MemoryKind::Conversation, &query.query, &response.text, None).await;
+                            Ok::<_, warp::Rejection>(warp::reply::json(&response.text))
+                        }
                         Err(e) => {
                             error!("Failed to process query via LLM: {:?}", e);
                             Err(warp::reject::custom(CustomError))
@@ -197,17 +451,156 @@ async fn main() {
                     }
                 });
 
+            let ask_llm_stream = warp::path!("ask_llm" / "stream")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and(state_filter.clone())
+                .and_then(|query: QueryInput, state: Arc<Mutex<SomeSharedState>>| async move {
+                    let tokens = {
+                        let state = state.lock().await.clone();
+                        let tasks = state.task_manager.get_tasks().await;
+                        let memories = state.memory.related(&query.query, None).await;
+                        match state.llm_client.process_query_stream(&query.query, &query.history, tasks, memories).await {
+                            Ok(tokens) => tokens,
+                            Err(e) => {
+                                error!("Failed to stream query via LLM: {:?}", e);
+                                return Err(warp::reject::custom(CustomError));
+                            }
+                        }
+                    };
+                    // One "token" event per token, then "done", or "error" if the stream breaks
+                    let events = tokens
+                        .map(|token| Ok::<_, Infallible>(match token {
+                            Ok(token) => Event::default().event("token").data(token),
+                            Err(e) => {
+                                error!("LLM stream failed: {:?}", e);
+                                Event::default().event("error").data(e.to_string())
+                            }
+                        }))
+                        .chain(stream::once(async { Ok(Event::default().event("done").data("")) }));
+                    Ok::<_, warp::Rejection>(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+                });
+
+            let search_memory = warp::path!("memory" / "search")
+                .and(warp::get())
+                .and(warp::query::<MemorySearchQuery>())
+                .and(state_filter.clone())
+                .and_then(|query: MemorySearchQuery, state: Arc<Mutex<SomeSharedState>>| async move {
+                    let state = state.lock().await.clone();
+                    let k = query.k.unwrap_or(state.memory.top_k());
+                    debug!("Searching memory for the {} memories closest to: {}", k, query.query);
+                    match state.memory.search(&query.query, k, None).await {
+                        Ok(hits) => Ok::<_, warp::Rejection>(warp::reply::json(&hits)),
+                        Err(e) => {
+                            error!("Failed to search memory: {:?}", e);
+                            Err(warp::reject::custom(CustomError))
+                        }
+                    }
+                });
+
+            let get_usage = warp::path!("usage")
+                .and(warp::get())
+                .and(state_filter.clone())
+                .and_then(|state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to get LLM usage");
+                    let state = state.lock().await;
+                    match state.usage.report().await {
+                        Ok(report) => Ok::<_, warp::Rejection>(warp::reply::json(&report)),
+                        Err(e) => {
+                            error!("Failed to get LLM usage: {:?}", e);
+                            Err(warp::reject::custom(CustomError))
+                        }
+                    }
+                });
+
+            let get_usage_calls = warp::path!("usage" / "calls")
+                .and(warp::get())
+                .and(warp::query::<UsageCallsQuery>())
+                .and(state_filter.clone())
+                .and_then(|query: UsageCallsQuery, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to get the last {} LLM calls", query.count);
+                    let state = state.lock().await;
+                    match state.usage.recent_calls(query.count).await {
+                        Ok(calls) => Ok::<_, warp::Rejection>(warp::reply::json(&calls)),
+                        Err(e) => {
+                            error!("Failed to get recent LLM calls: {:?}", e);
+                            Err(warp::reject::custom(CustomError))
+                        }
+                    }
+                });
+
+            let get_prompts = warp::path!("prompts")
+                .and(warp::get())
+                .and(state_filter.clone())
+                .and_then(|state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to list prompt templates");
+                    let state = state.lock().await;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&state.templates.list()))
+                });
+
+            let preview_prompt = warp::path!("prompts" / String)
+                .and(warp::get())
+                .and(warp::query::<TemplatePreviewQuery>())
+                .and(state_filter.clone())
+                .and_then(|name: String, query: TemplatePreviewQuery, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to preview prompt template: {}", name);
+                    let state = state.lock().await.clone();
+                    let Some(template) = state.templates.get(&name, query.version) else {
+                        return Err(warp::reject::not_found());
+                    };
+                    let task = match &query.task_id {
+                        Some(task_id) => state.task_manager.get_task(task_id).await.ok_or_else(warp::reject::not_found)?,
+                        None => Task::new("Preview of the prompt template", &name, false),
+                    };
+                    let budget = instruction_budget(state.llm_client.prompt_budget(Some(&task)).await, state.llm_client.system_prompt(Some(&task.action)));
+                    let rendered = state.templates.render(&template, &task, &state.task_manager, budget).await;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&json!({
+                        "template": template,
+                        "rendered": rendered,
+                    })))
+                });
+
+            let reload_prompts = warp::path!("prompts" / "reload")
+                .and(warp::post())
+                .and(state_filter.clone())
+                .and_then(|state: Arc<Mutex<SomeSharedState>>| async move {
+                    let state = state.lock().await;
+                    match state.templates.reload() {
+                        Ok(count) => {
+                            info!("Reloaded {} prompt templates via API", count);
+                            Ok::<_, warp::Rejection>(warp::reply::json(&state.templates.list()))
+                        }
+                        Err(e) => {
+                            error!("Failed to reload prompt templates: {:?}", e);
+                            Err(warp::reject::custom(CustomError))
+                        }
+                    }
+                });
+
+            let cancel_task = warp::path!("tasks" / String / "cancel")
+                .and(warp::post())
+                .and(state_filter.clone())
+                .and_then(|task_id: String, state: Arc<Mutex<SomeSharedState>>| async move {
+                    debug!("Received request to cancel task: {}", task_id);
+                    let state = state.lock().await;
+                    if !state.task_manager.cancel_task(&task_id) {
+                        return Err(warp::reject::not_found());
+                    }
+                    info!("Task cancelled via API: {}", task_id);
+                    Ok::<_, warp::Rejection>(warp::reply::with_status("Task cancelled", warp::http::StatusCode::OK))
+                });
+
             let status_route = warp::path("status")
                 .and(warp::get())
                 .and(state_filter)
                 .and_then(|state: Arc<Mutex<SomeSharedState>>| async move {
                     let state = state.lock().await;
-                    let status = state.get_status();
+                    let status = state.get_status().await;
                     debug!("Returning status: {:?}", status);
                     Ok::<_, warp::Rejection>(warp::reply::json(&status))
                 });
 
-            let routes = hello_route.or(get_tasks).or(add_task).or(validate_task).or(change_model).or(ask_llm).or(status_route);
+            let routes = hello_route.or(get_tasks).or(get_task).or(get_task_result).or(get_task_history).or(cancel_task).or(add_task).or(validate_task).or(get_dead_letter).or(replay_dead_letter).or(get_models).or(change_model).or(ask_llm).or(ask_llm_stream).or(search_memory).or(get_usage).or(get_usage_calls).or(get_prompts).or(reload_prompts).or(preview_prompt).or(status_route);
 
             // Combine routes and serve
             warp::serve(routes)
@@ -225,25 +618,48 @@ async fn main() {
     api_thread.join().unwrap();
 }
 
-// Example shared state struct
-#[derive(Debug)]
+// Example shared state struct. Its fields share their state with their
+// clones, so the handlers calling the LLM work on a clone instead of holding
+// the lock for the whole call.
+#[derive(Debug, Clone)]
 struct SomeSharedState {
     task_manager: TaskManager,
     llm_client: LLMClient,
+    tools: ToolRegistry,
+    usage: UsageStore,
+    templates: TemplateLibrary,
+    cache: ResponseCache,
+    memory: MemoryStore,
     // Add your fields here
 }
 
 impl SomeSharedState {
-    fn new(task_manager: TaskManager, llm_client: LLMClient) -> Self {
+    fn new(task_manager: TaskManager, llm_client: LLMClient, tools: ToolRegistry, usage: UsageStore, templates: TemplateLibrary, cache: ResponseCache, memory: MemoryStore) -> Self {
         SomeSharedState {
             task_manager,
             llm_client,
+            tools,
+            usage,
+            templates,
+            cache,
+            memory,
             // Initialize fields
         }
     }
 
-    fn get_status(&self) -> String {
+    async fn get_status(&self) -> Value {
         // Return detailed status of the program
-        format!("Tasks: {:?}, LLM Client: {:?}", self.task_manager, self.llm_client)
+        let cache = match self.cache.stats().await {
+            Ok(stats) => json!(stats),
+            Err(e) => json!(format!("Failed to read the cache counters: {}", e)),
+        };
+        json!({
+            "tasks": format!("{:?}", self.task_manager),
+            "llm_client": format!("{:?}", self.llm_client),
+            "llm_circuit_breaker": self.llm_client.circuit_status(),
+            "llm_embedding_circuit_breaker": self.llm_client.embedding_circuit_status(),
+            "llm_endpoints": self.llm_client.endpoints(),
+            "llm_cache": cache,
+        })
     }
 }
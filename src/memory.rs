@@ -0,0 +1,279 @@
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use uuid::Uuid;
+use crate::llm_client::LLMClient;
+use crate::task_manager::Task;
+
+/// Sorted set of the memory IDs, scored by creation time.
+const INDEX_KEY: &str = "memory:index";
+
+/// Characters of a memory sent to the embedding model; the rest is stored but not embedded.
+const MAX_EMBEDDED_CHARS: usize = 4000;
+
+/// Characters of a memory shown in a prompt.
+const PROMPT_EXCERPT_CHARS: usize = 500;
+
+/// Memory settings, read from the `[memory]` section of `config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MemoryConfig {
+    pub enabled: bool,
+    /// Memories added to a prompt.
+    pub top_k: usize,
+    /// Cosine similarity below which a memory is not considered related.
+    pub min_score: f32,
+    /// Memories kept; the oldest are forgotten first.
+    pub max_entries: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            enabled: false,
+            top_k: 3,
+            min_score: 0.5,
+            max_entries: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryKind {
+    /// What an action produced.
+    TaskResult,
+    /// A report written by an action.
+    Report,
+    /// A question asked through `/ask_llm` and its answer.
+    Conversation,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Memory {
+    pub id: String,
+    pub kind: MemoryKind,
+    /// What the memory answers: the task description or the question.
+    pub source: String,
+    pub text: String,
+    pub task_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A memory found by `search`, with its similarity to the query.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryHit {
+    pub memory: Memory,
+    pub score: f32,
+}
+
+fn memory_key(id: &str) -> String {
+    format!("memory:{}", id)
+}
+
+fn truncate(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+fn decode_vector(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm_a = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Long-term memory of what the agent produced and was told, searchable by
+/// meaning: every memory is stored in Redis with its embedding, and a search
+/// ranks them by cosine similarity to the embedding of the query.
+#[derive(Clone, Debug)]
+pub struct MemoryStore {
+    redis_client: Client,
+    llm_client: LLMClient,
+    config: MemoryConfig,
+}
+
+impl MemoryStore {
+    pub fn new(redis_client: Client, llm_client: LLMClient, config: MemoryConfig) -> Self {
+        MemoryStore { redis_client, llm_client, config }
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        self.redis_client.get_multiplexed_async_connection().await
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Memories added to a prompt, and returned by a search by default.
+    pub fn top_k(&self) -> usize {
+        self.config.top_k
+    }
+
+    /// Embeds `text`, accounting the call to `task`, or to the API without one.
+    async fn embed(&self, text: &str, task: Option<&Task>) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> {
+        let input = [truncate(text, MAX_EMBEDDED_CHARS).to_string()];
+        self.llm_client.embed(&input, task).await?
+            .pop()
+            .ok_or_else(|| "The embedding model returned no vector".into())
+    }
+
+    /// Embeds and stores `text`, produced by `task` if any. Failures are
+    /// logged, so remembering never fails the task or the query it comes from.
+    pub async fn remember(&self, kind: MemoryKind, source: &str, text: &str, task: Option<&Task>) {
+        if !self.config.enabled || text.trim().is_empty() {
+            return;
+        }
+        let memory = Memory {
+            id: Uuid::new_v4().to_string(),
+            kind,
+            source: source.to_string(),
+            text: text.to_string(),
+            task_id: task.map(|task| task.id.clone()),
+            created_at: Utc::now(),
+        };
+        match self.store(&memory, task).await {
+            Ok(()) => debug!("Remembered {:?} {} of \"{}\"", memory.kind, memory.id, memory.source),
+            Err(e) => warn!("Failed to remember {:?} of \"{}\": {}", memory.kind, memory.source, e),
+        }
+    }
+
+    async fn store(&self, memory: &Memory, task: Option<&Task>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let vector = self.embed(&format!("{}\n{}", memory.source, memory.text), task).await?;
+        let mut con = self.connection().await?;
+        let _: () = redis::pipe()
+            .atomic()
+            .hset(memory_key(&memory.id), "data", serde_json::to_string(memory)?).ignore()
+            .hset(memory_key(&memory.id), "vector", encode_vector(&vector)).ignore()
+            .zadd(INDEX_KEY, &memory.id, memory.created_at.timestamp_millis()).ignore()
+            .query_async(&mut con)
+            .await?;
+
+        // Forget the oldest memories past the limit
+        let count: usize = con.zcard(INDEX_KEY).await?;
+        if count > self.config.max_entries {
+            let forgotten: Vec<(String, f64)> = con.zpopmin(INDEX_KEY, (count - self.config.max_entries) as isize).await?;
+            let keys: Vec<String> = forgotten.iter().map(|(id, _)| memory_key(id)).collect();
+            let _: () = con.del(keys).await?;
+        }
+        Ok(())
+    }
+
+    /// The `k` memories most similar to `query`, most similar first. Memories
+    /// whose vectors don't have the query's dimension, because another model
+    /// embedded them, are skipped. The embedding of the query is accounted to
+    /// `task`, if any.
+    pub async fn search(&self, query: &str, k: usize, task: Option<&Task>) -> Result<Vec<MemoryHit>, Box<dyn Error + Send + Sync>> {
+        if !self.config.enabled {
+            return Err("Memory is disabled".into());
+        }
+        let query_vector = self.embed(query, task).await?;
+        let mut con = self.connection().await?;
+        let ids: Vec<String> = con.zrange(INDEX_KEY, 0, -1).await?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.cmd("HMGET").arg(memory_key(id)).arg("data").arg("vector");
+        }
+        let entries: Vec<(Option<String>, Option<Vec<u8>>)> = pipe.query_async(&mut con).await?;
+
+        let mut hits = Vec::new();
+        for (data, vector) in entries {
+            let (Some(data), Some(vector)) = (data, vector) else {
+                continue;
+            };
+            let vector = decode_vector(&vector);
+            if vector.len() != query_vector.len() {
+                continue;
+            }
+            hits.push(MemoryHit {
+                memory: serde_json::from_str(&data)?,
+                score: cosine_similarity(&query_vector, &vector),
+            });
+        }
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(k);
+        Ok(hits)
+    }
+
+    /// Prompt lines of the memories related to `query`, for a "Related
+    /// memories" section. Empty if memory is disabled or the search fails.
+    pub async fn related(&self, query: &str, task: Option<&Task>) -> Vec<String> {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+        match self.search(query, self.config.top_k, task).await {
+            Ok(hits) => hits.into_iter()
+                .filter(|hit| hit.score >= self.config.min_score)
+                .map(|hit| format!("- {} ({:?}, {}): {}",
+                    hit.memory.source,
+                    hit.memory.kind,
+                    hit.memory.created_at.format("%Y-%m-%d %H:%M"),
+                    truncate(&hit.memory.text, PROMPT_EXCERPT_CHARS)))
+                .collect(),
+            Err(e) => {
+                warn!("Failed to search memory: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors_survive_encoding() {
+        let vector = vec![0.5, -1.25, 3.0e-8, f32::MAX];
+        let bytes = encode_vector(&vector);
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(decode_vector(&bytes), vector);
+    }
+
+    #[test]
+    fn decoding_ignores_a_trailing_partial_value() {
+        let mut bytes = encode_vector(&[1.0, 2.0]);
+        bytes.push(0);
+        assert_eq!(decode_vector(&bytes), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn cosine_similarity_of_related_vectors() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]), 0.0);
+    }
+
+    #[test]
+    fn cosine_similarity_of_a_zero_vector_is_zero() {
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn truncates_on_character_boundaries() {
+        assert_eq!(truncate("héllo", 2), "hé");
+        assert_eq!(truncate("hi", 5), "hi");
+    }
+}
@@ -0,0 +1,348 @@
+use log::{debug, warn};
+use serde::Serialize;
+use crate::llm_client::ChatMessage;
+use crate::task_manager::{Task, TaskStatus};
+
+/// Context length assumed for models whose server doesn't report one.
+pub const DEFAULT_CONTEXT_LENGTH: usize = 4096;
+
+/// Tokens kept free for the reply when the generation options don't limit it.
+pub const DEFAULT_REPLY_TOKENS: usize = 1024;
+
+/// Section priorities: when the budget runs out, lower sections are cut first.
+pub const CONVERSATION_PRIORITY: i32 = 30;
+pub const DEPENDENCY_PRIORITY: i32 = 30;
+pub const ACTION_CONTEXT_PRIORITY: i32 = 20;
+pub const MEMORY_PRIORITY: i32 = 15;
+pub const TASKS_PRIORITY: i32 = 10;
+
+/// Tokens a chat message costs on top of its content.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// Rough token count of `text`, at about four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Tokens left for the instruction of a prompt of `budget` tokens with the
+/// `system` prompt, once both messages are accounted.
+pub fn instruction_budget(budget: usize, system: Option<&str>) -> usize {
+    let system = system.map_or(0, |system| estimate_tokens(system) + MESSAGE_OVERHEAD_TOKENS);
+    budget.saturating_sub(system + MESSAGE_OVERHEAD_TOKENS)
+}
+
+/// The newest of `lines`, given oldest first, that fit in `budget` tokens
+/// once joined with newlines.
+pub fn newest_lines_within(lines: &[String], budget: usize) -> &[String] {
+    let mut used = 0;
+    let mut start = lines.len();
+    for line in lines.iter().rev() {
+        used += estimate_tokens(line) + 1;
+        if used > budget {
+            break;
+        }
+        start -= 1;
+    }
+    &lines[start..]
+}
+
+/// One line per task, running and higher priority tasks first. Identical
+/// tasks share a line with their count.
+pub fn task_lines(tasks: &[Task]) -> Vec<String> {
+    let mut tasks: Vec<&Task> = tasks.iter().collect();
+    tasks.sort_by_key(|task| (task.status != TaskStatus::InProgress, -task.priority));
+    let mut lines: Vec<(String, usize)> = Vec::new();
+    for task in tasks {
+        let line = format!("- {} ({}, {:?})", task.description, task.action, task.status);
+        match lines.iter_mut().find(|(existing, _)| *existing == line) {
+            Some((_, count)) => *count += 1,
+            None => lines.push((line, 1)),
+        }
+    }
+    lines.into_iter()
+        .map(|(line, count)| if count > 1 { format!("{} x{}", line, count) } else { line })
+        .collect()
+}
+
+/// A titled block of context, cut item by item to fit the budget.
+#[derive(Debug, Clone)]
+pub struct ContextSection {
+    name: String,
+    priority: i32,
+    /// Most relevant first.
+    items: Vec<String>,
+    /// Items are shown in reverse, for chronological sections given newest first.
+    reversed: bool,
+}
+
+impl ContextSection {
+    /// `items` are shown in order and cut from the end.
+    pub fn new(name: &str, priority: i32, items: Vec<String>) -> Self {
+        ContextSection {
+            name: name.to_string(),
+            priority,
+            items,
+            reversed: false,
+        }
+    }
+
+    /// `items` are given oldest first, like log lines, and the oldest are cut first.
+    pub fn recent(name: &str, priority: i32, mut items: Vec<String>) -> Self {
+        items.reverse();
+        ContextSection {
+            name: name.to_string(),
+            priority,
+            items,
+            reversed: true,
+        }
+    }
+}
+
+/// Items of a section that didn't fit the budget.
+#[derive(Debug, Clone, Serialize)]
+pub struct DroppedContext {
+    pub section: String,
+    pub items: usize,
+    pub tokens: usize,
+}
+
+/// How a prompt was fitted in the budget.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PromptReport {
+    pub budget: usize,
+    /// Estimated tokens of the assembled prompt.
+    pub tokens: usize,
+    pub dropped: Vec<DroppedContext>,
+}
+
+impl PromptReport {
+    pub fn log(&self, what: &str) {
+        if self.dropped.is_empty() {
+            debug!("Prompt for {} uses about {} of {} tokens", what, self.tokens, self.budget);
+            return;
+        }
+        let dropped: Vec<String> = self.dropped.iter()
+            .map(|dropped| format!("{} {} items ({} tokens)", dropped.items, dropped.section, dropped.tokens))
+            .collect();
+        warn!("Prompt for {} cut to about {} of {} tokens, dropped {}", what, self.tokens, self.budget, dropped.join(", "));
+    }
+}
+
+/// Assembles chat messages that fit in a token budget. The system prompt and
+/// the instruction are always kept; context sections and the conversation
+/// history share the rest, highest priority first.
+#[derive(Debug, Clone)]
+pub struct PromptBuilder {
+    budget: usize,
+    system: Option<String>,
+    sections: Vec<ContextSection>,
+    history: Vec<ChatMessage>,
+    instruction: String,
+}
+
+impl PromptBuilder {
+    pub fn new(budget: usize, instruction: &str) -> Self {
+        PromptBuilder {
+            budget,
+            system: None,
+            sections: Vec::new(),
+            history: Vec::new(),
+            instruction: instruction.to_string(),
+        }
+    }
+
+    pub fn with_system(mut self, system: Option<&str>) -> Self {
+        self.system = system.map(str::to_string);
+        self
+    }
+
+    pub fn with_section(mut self, section: ContextSection) -> Self {
+        self.sections.push(section);
+        self
+    }
+
+    pub fn with_sections(mut self, sections: Vec<ContextSection>) -> Self {
+        self.sections.extend(sections);
+        self
+    }
+
+    /// Previous turns of the conversation, oldest first. The oldest are cut first.
+    pub fn with_history(mut self, history: &[ChatMessage]) -> Self {
+        self.history = history.to_vec();
+        self
+    }
+
+    pub fn build(self) -> (Vec<ChatMessage>, PromptReport) {
+        let mut used = estimate_tokens(&self.instruction) + MESSAGE_OVERHEAD_TOKENS;
+        if let Some(system) = &self.system {
+            used += estimate_tokens(system) + MESSAGE_OVERHEAD_TOKENS;
+        }
+        let mut report = PromptReport {
+            budget: self.budget,
+            ..PromptReport::default()
+        };
+
+        // The history competes with the sections as one more section, newest turns first
+        let history_items: Vec<String> = self.history.iter().rev().map(|message| message.content.clone()).collect();
+        let mut candidates: Vec<(&str, i32, &[String], usize)> = self.sections.iter()
+            .map(|section| (section.name.as_str(), section.priority, section.items.as_slice(), MESSAGE_OVERHEAD_TOKENS / 2))
+            .collect();
+        candidates.push(("conversation", CONVERSATION_PRIORITY, history_items.as_slice(), MESSAGE_OVERHEAD_TOKENS));
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by_key(|index| -candidates[*index].1);
+
+        let mut kept = vec![0; candidates.len()];
+        for index in order {
+            let (name, _, items, overhead) = candidates[index];
+            for item in items {
+                let mut cost = estimate_tokens(item) + overhead;
+                if kept[index] == 0 && index < self.sections.len() {
+                    cost += estimate_tokens(name) + 1;
+                }
+                if used + cost > self.budget {
+                    break;
+                }
+                used += cost;
+                kept[index] += 1;
+            }
+            if kept[index] < items.len() {
+                report.dropped.push(DroppedContext {
+                    section: name.to_string(),
+                    items: items.len() - kept[index],
+                    tokens: items[kept[index]..].iter().map(|item| estimate_tokens(item) + overhead).sum(),
+                });
+            }
+        }
+        report.tokens = used;
+
+        let mut messages = Vec::new();
+        if let Some(system) = &self.system {
+            messages.push(ChatMessage::system(system));
+        }
+        let kept_history = kept[self.sections.len()];
+        messages.extend_from_slice(&self.history[self.history.len() - kept_history..]);
+
+        let mut blocks: Vec<String> = self.sections.iter().zip(&kept)
+            .filter(|(_, kept)| **kept > 0)
+            .map(|(section, kept)| {
+                let mut items: Vec<&str> = section.items[..*kept].iter().map(String::as_str).collect();
+                if section.reversed {
+                    items.reverse();
+                }
+                format!("{}:\n{}", section.name, items.join("\n"))
+            })
+            .collect();
+        blocks.push(self.instruction);
+        messages.push(ChatMessage::user(&blocks.join("\n\n")));
+        (messages, report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_client::Role;
+
+    /// Text estimated at `tokens` tokens.
+    fn text(tokens: usize) -> String {
+        "x".repeat(tokens * 4)
+    }
+
+    #[test]
+    fn keeps_everything_that_fits() {
+        let history = [ChatMessage::user("hello"), ChatMessage::assistant("hi")];
+        let (messages, report) = PromptBuilder::new(1000, "Do it")
+            .with_system(Some("You are a test"))
+            .with_section(ContextSection::new("Facts", 20, vec!["one".to_string(), "two".to_string()]))
+            .with_history(&history)
+            .build();
+
+        assert!(report.dropped.is_empty());
+        assert!(report.tokens <= report.budget);
+        let roles: Vec<&Role> = messages.iter().map(|message| &message.role).collect();
+        assert_eq!(roles, [&Role::System, &Role::User, &Role::Assistant, &Role::User]);
+        assert_eq!(messages[3].content, "Facts:\none\ntwo\n\nDo it");
+    }
+
+    #[test]
+    fn drops_the_lowest_priority_section_first() {
+        // Instruction "go": 1 token + 4 overhead. Each section: 10 tokens + 2 overhead + 2 for its title.
+        let (messages, report) = PromptBuilder::new(5 + 14 + 5, "go")
+            .with_section(ContextSection::new("Low", 10, vec![text(10)]))
+            .with_section(ContextSection::new("High", 20, vec![text(10)]))
+            .build();
+
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].content.starts_with("High:\n"));
+        assert!(!messages[0].content.contains("Low:"));
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(report.dropped[0].section, "Low");
+        assert_eq!(report.dropped[0].items, 1);
+        assert_eq!(report.dropped[0].tokens, 12);
+        assert_eq!(report.tokens, 19);
+    }
+
+    #[test]
+    fn cuts_the_oldest_turns_of_the_history() {
+        let history = [ChatMessage::user(&text(10)), ChatMessage::assistant("second"), ChatMessage::user("third")];
+        // Room for the instruction and the two newest turns, not the oldest
+        let (messages, report) = PromptBuilder::new(5 + 6 + 6 + 5, "go")
+            .with_history(&history)
+            .build();
+
+        let contents: Vec<&str> = messages.iter().map(|message| message.content.as_str()).collect();
+        assert_eq!(contents, ["second", "third", "go"]);
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(report.dropped[0].section, "conversation");
+        assert_eq!(report.dropped[0].items, 1);
+        assert_eq!(report.dropped[0].tokens, 14);
+    }
+
+    #[test]
+    fn recent_sections_keep_the_newest_items_in_order() {
+        let logs = vec!["old".to_string(), "mid".to_string(), "new".to_string()];
+        // Title 2 tokens, each line 1 token + 2 overhead: room for two lines
+        let (messages, report) = PromptBuilder::new(5 + 2 + 3 + 3, "go")
+            .with_section(ContextSection::recent("Logs", 20, logs))
+            .build();
+
+        assert_eq!(messages[0].content, "Logs:\nmid\nnew\n\ngo");
+        assert_eq!(report.dropped[0].items, 1);
+    }
+
+    #[test]
+    fn always_keeps_the_system_prompt_and_instruction() {
+        let (messages, report) = PromptBuilder::new(0, &text(100))
+            .with_system(Some("system"))
+            .with_section(ContextSection::new("Facts", 20, vec!["fact".to_string()]))
+            .build();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content, text(100));
+        assert!(report.tokens > report.budget);
+        assert_eq!(report.dropped[0].section, "Facts");
+    }
+
+    #[test]
+    fn keeps_the_newest_lines_that_fit() {
+        let lines = vec![text(3), text(1), text(1)];
+        assert_eq!(newest_lines_within(&lines, 4), &lines[1..]);
+        assert_eq!(newest_lines_within(&lines, 8), &lines[..]);
+        assert!(newest_lines_within(&lines, 1).is_empty());
+    }
+
+    #[test]
+    fn task_lines_group_identical_tasks() {
+        let mut running = Task::new("Check", "check_status", false);
+        running.status = TaskStatus::InProgress;
+        let tasks = [
+            Task::new("Report", "write_detailed_report", false).with_priority(5),
+            Task::new("Report", "write_detailed_report", false).with_priority(5),
+            running,
+        ];
+        assert_eq!(task_lines(&tasks), [
+            "- Check (check_status, InProgress)",
+            "- Report (write_detailed_report, Pending) x2",
+        ]);
+    }
+}
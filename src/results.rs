@@ -0,0 +1,124 @@
+use chrono::{DateTime, Utc};
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::Duration;
+
+/// Default time results are kept after the last run of their task.
+const DEFAULT_RESULT_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Default number of runs kept in a task's history.
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+
+/// Output of one run of a task.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskResult {
+    pub task_id: String,
+    pub action: String,
+    /// Execution attempt of the task that produced this result.
+    pub attempt: u32,
+    pub output: String,
+    /// LLM model used, if the action called the LLM.
+    pub model: Option<String>,
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    pub latency_ms: u64,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// Retention settings, read from the `[results]` section of `config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ResultsConfig {
+    pub ttl_secs: u64,
+    pub history_limit: usize,
+}
+
+impl Default for ResultsConfig {
+    fn default() -> Self {
+        ResultsConfig {
+            ttl_secs: DEFAULT_RESULT_TTL.as_secs(),
+            history_limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
+}
+
+/// Stores the latest result of every task plus a capped run history, so
+/// recurring tasks keep their previous outputs.
+#[derive(Clone, Debug)]
+pub struct ResultStore {
+    redis_client: Client,
+    ttl: Duration,
+    history_limit: usize,
+}
+
+/// Latest result of a task, also referenced by `Task.result_key`.
+pub fn result_key(task_id: &str) -> String {
+    format!("result:{}", task_id)
+}
+
+fn history_key(task_id: &str) -> String {
+    format!("results:history:{}", task_id)
+}
+
+impl ResultStore {
+    pub fn new(redis_client: Client) -> Self {
+        ResultStore {
+            redis_client,
+            ttl: DEFAULT_RESULT_TTL,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
+
+    pub fn with_config(mut self, config: &ResultsConfig) -> Self {
+        self.ttl = Duration::from_secs(config.ttl_secs);
+        self.history_limit = config.history_limit.max(1);
+        self
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        self.redis_client.get_multiplexed_async_connection().await
+    }
+
+    /// Records `result` as the latest run of its task and prepends it to the history.
+    pub async fn store(&self, result: &TaskResult) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let serialized_result = serde_json::to_string(result)?;
+        let key = result_key(&result.task_id);
+        let history = history_key(&result.task_id);
+        let ttl = self.ttl.as_secs().max(1) as i64;
+
+        let mut con = self.connection().await?;
+        let _: () = redis::pipe()
+            .atomic()
+            .set_ex(&key, &serialized_result, ttl as u64).ignore()
+            .lpush(&history, &serialized_result).ignore()
+            .ltrim(&history, 0, self.history_limit as isize - 1).ignore()
+            .expire(&history, ttl).ignore()
+            .query_async(&mut con)
+            .await?;
+        Ok(key)
+    }
+
+    /// Latest result of a task, if it ran within the retention period.
+    pub async fn latest(&self, task_id: &str) -> Result<Option<TaskResult>, Box<dyn Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let result_json: Option<String> = con.get(result_key(task_id)).await?;
+        match result_json {
+            Some(result_json) => Ok(Some(serde_json::from_str(&result_json)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Results of the last runs of a task, newest first.
+    pub async fn history(&self, task_id: &str) -> Result<Vec<TaskResult>, Box<dyn Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let results_json: Vec<String> = con.lrange(history_key(task_id), 0, -1).await?;
+        let mut results = Vec::new();
+        for result_json in results_json {
+            results.push(serde_json::from_str(&result_json)?);
+        }
+        Ok(results)
+    }
+}
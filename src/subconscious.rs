@@ -1,31 +1,129 @@
+use crate::actions::ActionRegistry;
 use crate::task_manager::TaskManager;
 use crate::llm_client::LLMClient;
+use crate::memory::MemoryStore;
+use crate::templates::TemplateLibrary;
+use crate::tools::ToolRegistry;
+use log::error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, Duration};
+
+/// How long a task is put back when every slot of its action is taken.
+const ACTION_LIMIT_RETRY_SECS: i64 = 2;
+
+/// Worker pool settings, read from the `[executor]` section of `config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExecutorConfig {
+    /// Number of tasks executed concurrently.
+    pub workers: usize,
+    /// Maximum number of concurrently running tasks per action.
+    pub action_limits: HashMap<String, usize>,
+}
+
+impl Default for ExecutorConfig {
+    fn default() -> Self {
+        ExecutorConfig {
+            workers: 4,
+            action_limits: HashMap::new(),
+        }
+    }
+}
 
 pub struct Subconscious {
     pub task_manager: TaskManager,
     pub llm_client: LLMClient,
+    pub actions: ActionRegistry,
+    pub tools: ToolRegistry,
+    pub templates: TemplateLibrary,
+    pub memory: Option<MemoryStore>,
+    pub workers: usize,
+    action_limits: HashMap<String, Arc<Semaphore>>,
 }
 
 impl Subconscious {
     pub fn new(task_manager: TaskManager, llm_client: LLMClient) -> Self {
+        let actions = ActionRegistry::with_builtin_actions();
         Subconscious {
             task_manager,
             llm_client,
+            tools: ToolRegistry::with_builtin_tools(actions.actions()),
+            actions,
+            templates: TemplateLibrary::default(),
+            memory: None,
+            workers: 1,
+            action_limits: HashMap::new(),
         }
     }
 
-    pub async fn process_tasks(&self) {
-        self.task_manager.execute_tasks(&self.llm_client).await;
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = tools;
+        self
     }
 
-    pub async fn add_routine_task(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let task = crate::task_manager::Task {
-            description: "Routine check".to_string(),
-            action: "Perform routine check".to_string(),
-            status: crate::task_manager::TaskStatus::Pending,
-            is_permanent: false,
-        };
-        self.task_manager.add_task(task).await?;
-        Ok(())
+    pub fn with_templates(mut self, templates: TemplateLibrary) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Remembers the output of the tasks in `memory`, and adds related
+    /// memories to their prompts.
+    pub fn with_memory(mut self, memory: MemoryStore) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub fn with_executor_config(mut self, config: &ExecutorConfig) -> Self {
+        self.workers = config.workers.max(1);
+        self.action_limits = config.action_limits.iter()
+            .map(|(action, limit)| (action.clone(), Arc::new(Semaphore::new((*limit).max(1)))))
+            .collect();
+        self
+    }
+
+    /// Claims and executes the next due task, if any. Safe to call from
+    /// several workers at once; tasks of a rate limited action are deferred
+    /// while every slot of the action is taken, and tasks calling the LLM
+    /// while its circuit breaker is open.
+    pub async fn process_tasks(&self) {
+        if let Err(e) = self.task_manager.promote_due_tasks().await {
+            error!("Failed to promote due tasks: {}", e);
+        }
+        match self.task_manager.claim_next_task().await {
+            Ok(Some(task)) => {
+                // Don't hammer a dead LLM endpoint; the task runs once the breaker lets calls through
+                if let Some(open_until) = self.llm_client.circuit_open_until() {
+                    if self.actions.uses_llm(&task.action) {
+                        if let Err(e) = self.task_manager.defer_task(&task, open_until).await {
+                            error!("Failed to defer task {}: {}", task.id, e);
+                        }
+                        return;
+                    }
+                }
+                // Waiting for a slot would hold the worker and let the task's lease run out
+                let _permit = match self.action_limits.get(&task.action) {
+                    Some(limit) => match limit.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            let retry_at = chrono::Utc::now() + chrono::Duration::seconds(ACTION_LIMIT_RETRY_SECS);
+                            if let Err(e) = self.task_manager.defer_task(&task, retry_at).await {
+                                error!("Failed to defer task {}: {}", task.id, e);
+                            }
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+                self.task_manager.execute_task(task, &self.actions, &self.tools, &self.templates, &self.llm_client, self.memory.as_ref()).await;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to claim next task: {}", e);
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
     }
 }
@@ -1,124 +1,1368 @@
+use chrono::{DateTime, Utc};
+use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
-use redis::Client;
+use redis::{Client, Script};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use rand::Rng;
+use uuid::Uuid;
 use log::{info, error, debug};
-use crate::llm_client::LLMClient;
+use crate::actions::{ActionContext, ActionRegistry};
+use crate::circuit_breaker::CircuitOpen;
+use crate::llm_client::{GenerationOptions, LLMClient};
+use crate::memory::MemoryStore;
+use crate::results::{ResultStore, ResultsConfig, TaskResult};
+use crate::templates::TemplateLibrary;
+use crate::tools::ToolRegistry;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Task {
+    /// Unique task identifier, assigned by `TaskManager::add_task` when empty.
+    #[serde(default)]
+    pub id: String,
     pub description: String,
     pub action: String,
+    #[serde(default)]
     pub status: TaskStatus,
+    #[serde(default)]
     pub is_permanent: bool,
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Number of times execution of this task has been started.
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Redis key holding the `TaskResult` of the last successful run.
+    #[serde(default)]
+    pub result_key: Option<String>,
+    /// How a permanent task is re-armed after each run.
+    #[serde(default)]
+    pub recurrence: Option<Recurrence>,
+    /// When a re-armed, time based permanent task is due again.
+    #[serde(default)]
+    pub next_run_at: Option<DateTime<Utc>>,
+    /// IDs of the tasks whose results this task needs. The task stays `Blocked`
+    /// until all of them have completed.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Higher runs first, see `PRIORITY_AGING_SECS`.
+    #[serde(default)]
+    pub priority: i32,
+    /// Overrides the task manager's default retry policy.
+    #[serde(default)]
+    pub retry_policy: Option<RetryPolicy>,
+    /// Overrides the global and per-action LLM generation options.
+    #[serde(default)]
+    pub options: Option<GenerationOptions>,
+    /// Labels routing the task to a model, see `[llm.routing.tags]`.
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
+impl Task {
+    pub fn new(description: &str, action: &str, is_permanent: bool) -> Self {
+        let now = Utc::now();
+        Task {
+            id: Uuid::new_v4().to_string(),
+            description: description.to_string(),
+            action: action.to_string(),
+            status: TaskStatus::Pending,
+            is_permanent,
+            created_at: now,
+            updated_at: now,
+            started_at: None,
+            finished_at: None,
+            attempts: 0,
+            last_error: None,
+            result_key: None,
+            recurrence: None,
+            next_run_at: None,
+            depends_on: Vec::new(),
+            priority: 0,
+            retry_policy: None,
+            options: None,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = id.to_string();
+        self
+    }
+
+    pub fn with_dependencies(mut self, depends_on: Vec<String>) -> Self {
+        self.depends_on = depends_on;
+        self
+    }
+
+    /// Whether tasks depending on this one may run: it completed, or, for a
+    /// permanent task, it completed at least one run.
+    pub fn satisfies_dependents(&self) -> bool {
+        self.status == TaskStatus::Completed || (self.is_permanent && self.result_key.is_some())
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Like `with_priority`, for a priority the LLM chose: clamped so a
+    /// generated task never outranks a task submitted with the default priority.
+    pub fn with_generated_priority(mut self, priority: i64) -> Self {
+        self.priority = priority.clamp(MIN_GENERATED_PRIORITY as i64, 0) as i32;
+        self
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn with_recurrence(mut self, recurrence: Recurrence) -> Self {
+        self.recurrence = Some(recurrence);
+        self
+    }
+
+    /// Puts a permanent task back to `Pending` for its next run.
+    fn rearm(&mut self, next_run: &NextRun) {
+        self.set_status(TaskStatus::Pending);
+        self.attempts = 0;
+        self.next_run_at = match next_run {
+            NextRun::At(at) => Some(*at),
+            NextRun::AfterCompletions(_) => None,
+        };
+    }
+
+    /// Moves the task to `status`, keeping the lifecycle timestamps in sync.
+    pub fn set_status(&mut self, status: TaskStatus) {
+        let now = Utc::now();
+        match status {
+            TaskStatus::InProgress => {
+                self.started_at = Some(now);
+                self.attempts += 1;
+            }
+            TaskStatus::Completed | TaskStatus::Failed => self.finished_at = Some(now),
+            TaskStatus::Pending | TaskStatus::Blocked => {}
+        }
+        self.status = status;
+        self.updated_at = now;
+    }
+}
+
+/// Re-arm policy of a permanent task. Permanent tasks without one run every
+/// `DEFAULT_RECURRENCE_SECS` seconds.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Recurrence {
+    /// Run again `seconds` after the previous run completed.
+    Interval { seconds: u64 },
+    /// Run again at the next time matching a cron expression (with seconds field).
+    Cron { expression: String },
+    /// Run again once `count` other tasks have completed.
+    AfterTasks { count: u64 },
+}
+
+impl Recurrence {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            Recurrence::Interval { seconds: 0 } => Err("Recurrence interval must be at least one second".into()),
+            Recurrence::Interval { seconds } if *seconds > MAX_RECURRENCE_SECS => Err(format!(
+                "Recurrence interval must be at most {} seconds", MAX_RECURRENCE_SECS).into()),
+            Recurrence::AfterTasks { count: 0 } => Err("Recurrence task count must be at least one".into()),
+            Recurrence::Cron { expression } => {
+                cron::Schedule::from_str(expression).map_err(|e| format!("Invalid cron expression {:?}: {}", expression, e))?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Computes when the task is due again, given the global completion counter.
+    fn next_run(&self, completed_count: u64) -> Result<NextRun, Box<dyn std::error::Error + Send + Sync>> {
+        let now = Utc::now();
+        match self {
+            Recurrence::Interval { seconds } => i64::try_from(*seconds).ok()
+                .and_then(chrono::Duration::try_seconds)
+                .and_then(|interval| now.checked_add_signed(interval))
+                .map(NextRun::At)
+                .ok_or_else(|| format!("Recurrence interval of {} seconds is out of range", seconds).into()),
+            Recurrence::Cron { expression } => {
+                let schedule = cron::Schedule::from_str(expression)?;
+                schedule.after(&now).next()
+                    .map(NextRun::At)
+                    .ok_or_else(|| format!("Cron expression {:?} has no upcoming run", expression).into())
+            }
+            Recurrence::AfterTasks { count } => completed_count.checked_add(*count)
+                .map(NextRun::AfterCompletions)
+                .ok_or_else(|| format!("Recurrence task count {} is out of range", count).into()),
+        }
+    }
+}
+
+/// How often and how quickly a failing task is retried.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Executions after which the task is moved to the dead-letter list.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every further attempt.
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+    /// Random fraction (0 to 1) by which each delay is stretched or shrunk.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay_secs: 5,
+            max_delay_secs: 300,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.max_delay_secs > MAX_RETRY_DELAY_SECS {
+            return Err(format!("Retry max_delay_secs must be at most {} seconds", MAX_RETRY_DELAY_SECS).into());
+        }
+        if self.base_delay_secs > self.max_delay_secs {
+            return Err("Retry base_delay_secs must be at most max_delay_secs".into());
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err("Retry jitter must be between 0 and 1".into());
+        }
+        Ok(())
+    }
+
+    /// Delay before retrying a task that failed its `attempts`-th execution.
+    pub fn backoff(&self, attempts: u32) -> chrono::Duration {
+        let exponent = attempts.saturating_sub(1).min(32);
+        let delay = self.base_delay_secs.saturating_mul(1u64 << exponent).min(self.max_delay_secs) as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 { 1.0 + rand::thread_rng().gen_range(-jitter..=jitter) } else { 1.0 };
+        chrono::Duration::milliseconds((delay * factor * 1000.0) as i64)
+    }
+}
+
+/// When a re-armed permanent task goes back to the queue.
+enum NextRun {
+    At(DateTime<Utc>),
+    /// Once the global completion counter reaches this value.
+    AfterCompletions(u64),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
 pub enum TaskStatus {
+    #[default]
     Pending,
+    /// Waiting for the tasks in `depends_on` to complete.
+    Blocked,
     InProgress,
     Completed,
+    /// Exhausted its retries, see `last_error`. Kept in the dead-letter list.
+    Failed,
+}
+
+impl TaskStatus {
+    pub const ALL: [TaskStatus; 5] = [TaskStatus::Pending, TaskStatus::Blocked, TaskStatus::InProgress, TaskStatus::Completed, TaskStatus::Failed];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskStatus::Pending => "pending",
+            TaskStatus::Blocked => "blocked",
+            TaskStatus::InProgress => "in_progress",
+            TaskStatus::Completed => "completed",
+            TaskStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Sorted set of task IDs waiting to be executed, scored by `queue_score`.
+const QUEUE_KEY: &str = "tasks:queue";
+
+/// List of task IDs currently being executed.
+const PROCESSING_KEY: &str = "tasks:processing";
+
+/// Sorted set of in-flight task IDs scored by the unix time their lease expires.
+const LEASES_KEY: &str = "tasks:leases";
+
+/// How long a worker waits for a task before giving the loop back.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often an empty queue is polled while waiting for a task.
+const CLAIM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Seconds of waiting each priority point is worth. A task with priority 1
+/// runs before a priority 0 task queued up to this long before it, so
+/// low-priority work still runs once it has waited long enough.
+const PRIORITY_AGING_SECS: i64 = 60;
+
+/// Priority of the routine check seeded at startup.
+pub const ROUTINE_PRIORITY: i32 = -5;
+
+/// Lowest priority the LLM may give the tasks it queues.
+const MIN_GENERATED_PRIORITY: i32 = -10;
+
+/// Default time an in-flight task may run before it is handed to another worker.
+const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Default time a completed or failed one-shot task is kept.
+const DEFAULT_FINISHED_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Sorted set of re-armed permanent and retried task IDs scored by the unix time they are due.
+const SCHEDULED_KEY: &str = "tasks:scheduled";
+
+/// Sorted set of re-armed permanent task IDs scored by the completion count they wait for.
+const SCHEDULED_AFTER_COUNT_KEY: &str = "tasks:scheduled_after_count";
+
+/// Number of task runs completed so far, used by `Recurrence::AfterTasks`.
+const COMPLETED_COUNT_KEY: &str = "tasks:completed_count";
+
+/// Re-arm interval of permanent tasks without a recurrence policy.
+const DEFAULT_RECURRENCE_SECS: u64 = 60;
+
+/// Longest interval a recurrence may have: one year.
+const MAX_RECURRENCE_SECS: u64 = 365 * 24 * 3600;
+
+/// Longest delay a retry policy may wait before a retry: one day.
+const MAX_RETRY_DELAY_SECS: u64 = 24 * 3600;
+
+/// List of IDs of tasks that exhausted their retries, oldest first.
+const DEAD_LETTER_KEY: &str = "tasks:dead_letter";
+
+/// How many times a compare-and-set on a task is retried before giving up.
+const MAX_UPDATE_ATTEMPTS: usize = 10;
+
+fn task_key(task_id: &str) -> String {
+    format!("task:{}", task_id)
+}
+
+/// Set of IDs of blocked tasks waiting for the given task to complete.
+fn dependents_key(task_id: &str) -> String {
+    format!("tasks:dependents:{}", task_id)
+}
+
+fn status_key(status: &TaskStatus) -> String {
+    format!("tasks:status:{}", status.as_str())
+}
+
+/// Queue score of a task with `priority` queued at `queued_at`; lowest runs first.
+fn queue_score(priority: i32, queued_at: DateTime<Utc>) -> i64 {
+    queued_at.timestamp() - priority as i64 * PRIORITY_AGING_SECS
 }
 
+/// Creates the task hash, indexes it by status and queues it if pending, unless
+/// the ID is taken. Registers the task as a dependent of every task in KEYS[4..].
+/// KEYS: task hash, status set, queue, dependents sets. ARGV: id, data, status, priority, queue score.
+static ADD_TASK_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'data', ARGV[2], 'status', ARGV[3], 'priority', ARGV[4], 'rev', 0)
+redis.call('SADD', KEYS[2], ARGV[1])
+if ARGV[3] == 'pending' then
+    redis.call('ZADD', KEYS[3], ARGV[5], ARGV[1])
+end
+for i = 4, #KEYS do
+    redis.call('SADD', KEYS[i], ARGV[1])
+end
+return 1
+"#));
+
+/// Replaces the task data if nobody else changed it since it was read and moves
+/// the ID between status sets. Returns 1 on success, 0 on a revision conflict
+/// and -1 when the task does not exist.
+/// KEYS: task hash, old status set, new status set. ARGV: id, expected rev, data, status, priority.
+static UPDATE_TASK_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r#"
+local rev = redis.call('HGET', KEYS[1], 'rev')
+if not rev then
+    return -1
+end
+if rev ~= ARGV[2] then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'data', ARGV[3], 'status', ARGV[4], 'priority', ARGV[5], 'rev', tonumber(rev) + 1)
+redis.call('SREM', KEYS[2], ARGV[1])
+redis.call('SADD', KEYS[3], ARGV[1])
+return 1
+"#));
+
+/// Pops the best scored task from the queue onto the processing list under a lease.
+/// KEYS: queue, processing list, leases. ARGV: lease deadline.
+static CLAIM_TASK_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r#"
+local popped = redis.call('ZPOPMIN', KEYS[1])
+if #popped == 0 then
+    return false
+end
+redis.call('RPUSH', KEYS[2], popped[1])
+redis.call('ZADD', KEYS[3], ARGV[1], popped[1])
+return popped[1]
+"#));
+
+/// Moves a task from the processing list back to the queue, if it is still there.
+/// KEYS: processing list, leases, queue. ARGV: id, queue score.
+static REQUEUE_TASK_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r#"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+    return 0
+end
+redis.call('ZREM', KEYS[2], ARGV[1])
+redis.call('ZADD', KEYS[3], ARGV[2], ARGV[1])
+return 1
+"#));
+
+/// Moves every ID scored at most ARGV[1] from a schedule to the queue, scoring
+/// each by the priority stored in its task hash.
+/// KEYS: schedule, queue. ARGV: max score, now, priority aging seconds.
+static PROMOTE_TASKS_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r#"
+local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+for _, id in ipairs(ids) do
+    local priority = tonumber(redis.call('HGET', 'task:' .. id, 'priority') or '0')
+    redis.call('ZREM', KEYS[1], id)
+    redis.call('ZADD', KEYS[2], tonumber(ARGV[2]) - priority * tonumber(ARGV[3]), id)
+end
+return #ids
+"#));
+
+/// Moves a task from the dead-letter list to the queue, if it is there.
+/// KEYS: dead-letter list, queue. ARGV: id, queue score.
+static REPLAY_TASK_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r#"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+    return 0
+end
+redis.call('ZADD', KEYS[2], ARGV[2], ARGV[1])
+return 1
+"#));
+
 #[derive(Clone, Debug)]
 pub struct TaskManager {
     redis_client: Arc<Mutex<Client>>,
+    pub results: ResultStore,
+    visibility_timeout: Duration,
+    /// How long completed and failed one-shot tasks are kept.
+    finished_ttl: Duration,
+    retry_policy: RetryPolicy,
+    /// Tokens of the tasks running in this process, to cancel them from the API.
+    running: Arc<std::sync::Mutex<HashMap<String, CancellationToken>>>,
 }
 
 impl TaskManager {
     pub fn new(redis_url: &str) -> Self {
         let client = Client::open(redis_url).expect("Invalid Redis URL");
         TaskManager {
+            results: ResultStore::new(client.clone()),
             redis_client: Arc::new(Mutex::new(client)),
+            visibility_timeout: DEFAULT_VISIBILITY_TIMEOUT,
+            finished_ttl: DEFAULT_FINISHED_TTL,
+            retry_policy: RetryPolicy::default(),
+            running: Arc::new(std::sync::Mutex::new(HashMap::new())),
         }
     }
 
-    pub async fn add_task(&self, task: Task) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    pub fn with_results_config(mut self, config: &ResultsConfig) -> Self {
+        self.results = self.results.with_config(config);
+        self
+    }
+
+    /// Sets the retry policy of tasks that don't define their own.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets how long an in-flight task may run before its lease expires and
+    /// it is handed back to the queue.
+    pub fn with_visibility_timeout(mut self, visibility_timeout: Duration) -> Self {
+        self.visibility_timeout = visibility_timeout;
+        self
+    }
+
+    /// Sets how long completed and failed one-shot tasks are kept before
+    /// they expire. Permanent tasks are kept.
+    pub fn with_finished_ttl(mut self, finished_ttl: Duration) -> Self {
+        self.finished_ttl = finished_ttl;
+        self
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        self.redis_client.lock().await.get_multiplexed_async_connection().await
+    }
+
+    /// Queues `task` and returns its ID, generating one if the task has none.
+    pub async fn add_task(&self, mut task: Task) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        if task.id.is_empty() {
+            task.id = Uuid::new_v4().to_string();
+        }
+        if let Some(recurrence) = &task.recurrence {
+            recurrence.validate()?;
+        }
+        if let Some(retry_policy) = &task.retry_policy {
+            retry_policy.validate()?;
+        }
+        let unsatisfied = self.check_dependencies(&task).await?;
+        if !unsatisfied.is_empty() && task.status == TaskStatus::Pending {
+            task.status = TaskStatus::Blocked;
+        }
+
         let serialized_task = serde_json::to_string(&task)?;
-        let mut con = self.redis_client.lock().await.get_multiplexed_async_connection().await?;
-        con.lpush("tasks", serialized_task).await?;
+        let mut con = self.connection().await?;
+        let mut invocation = ADD_TASK_SCRIPT.prepare_invoke();
+        invocation
+            .key(task_key(&task.id))
+            .key(status_key(&task.status))
+            .key(QUEUE_KEY);
+        for dependency in &unsatisfied {
+            invocation.key(dependents_key(dependency));
+        }
+        let added: i32 = invocation
+            .arg(&task.id)
+            .arg(serialized_task)
+            .arg(task.status.as_str())
+            .arg(task.priority)
+            .arg(queue_score(task.priority, Utc::now()))
+            .invoke_async(&mut con)
+            .await?;
+        if added == 0 {
+            return Err(format!("Task already exists: {}", task.id).into());
+        }
+
+        // A dependency may have completed while the task was being stored
+        if task.status == TaskStatus::Blocked {
+            self.unblock_if_ready(&task.id).await?;
+        }
+        Ok(task.id)
+    }
+
+    /// Checks that every dependency of `task` exists and that depending on them
+    /// would not create a cycle. Returns the dependencies that are not satisfied yet.
+    async fn check_dependencies(&self, task: &Task) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut unsatisfied = Vec::new();
+        for dependency_id in &task.depends_on {
+            match self.get_task(dependency_id).await {
+                Some(dependency) if dependency.status == TaskStatus::Failed => {
+                    return Err(format!("Dependency {} failed: {}", dependency_id, dependency.last_error.unwrap_or_default()).into());
+                }
+                Some(dependency) if !dependency.satisfies_dependents() => unsatisfied.push(dependency_id.clone()),
+                Some(_) => {}
+                None => return Err(format!("Unknown dependency: {}", dependency_id).into()),
+            }
+        }
+
+        // Walk the dependency graph looking for a path back to the task
+        let mut visited = HashSet::new();
+        let mut stack = task.depends_on.clone();
+        while let Some(task_id) = stack.pop() {
+            if task_id == task.id {
+                return Err(format!("Dependency cycle detected through task {}", task.id).into());
+            }
+            if !visited.insert(task_id.clone()) {
+                continue;
+            }
+            if let Some(dependency) = self.get_task(&task_id).await {
+                stack.extend(dependency.depends_on);
+            }
+        }
+        Ok(unsatisfied)
+    }
+
+    /// Queues a blocked task once all of its dependencies are satisfied.
+    /// Returns whether the task was unblocked.
+    async fn unblock_if_ready(&self, task_id: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let task = match self.get_task(task_id).await {
+            Some(task) if task.status == TaskStatus::Blocked => task,
+            _ => return Ok(false),
+        };
+        for dependency_id in &task.depends_on {
+            match self.get_task(dependency_id).await {
+                Some(dependency) if dependency.satisfies_dependents() => {}
+                _ => return Ok(false),
+            }
+        }
+
+        let mut unblocked = false;
+        let task = self.update_task(task_id, |task| {
+            unblocked = task.status == TaskStatus::Blocked;
+            if unblocked {
+                task.set_status(TaskStatus::Pending);
+            }
+        }).await?;
+        if !unblocked {
+            return Ok(false);
+        }
+
+        let mut con = self.connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().zadd(QUEUE_KEY, task_id, queue_score(task.priority, Utc::now())).ignore();
+        for dependency_id in &task.depends_on {
+            pipe.srem(dependents_key(dependency_id), task_id).ignore();
+        }
+        let _: () = pipe.query_async(&mut con).await?;
+        info!("Dependencies of task {} completed, queued it", task_id);
+        Ok(true)
+    }
+
+    /// Queues the blocked dependents of a task that just completed a run.
+    async fn unblock_dependents(&self, task_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let dependents: Vec<String> = con.smembers(dependents_key(task_id)).await?;
+        for dependent in dependents {
+            self.unblock_if_ready(&dependent).await?;
+        }
+        Ok(())
+    }
+
+    /// Collects the latest results of the tasks `task` depends on, for prompt context.
+    pub async fn get_dependency_results(&self, task: &Task) -> Vec<(Task, String)> {
+        let mut results = Vec::new();
+        for dependency_id in &task.depends_on {
+            let dependency = match self.get_task(dependency_id).await {
+                Some(dependency) => dependency,
+                None => continue,
+            };
+            match self.results.latest(dependency_id).await {
+                Ok(Some(result)) => results.push((dependency, result.output)),
+                Ok(None) => {}
+                Err(e) => error!("Failed to get result of task {}: {}", dependency_id, e),
+            }
+        }
+        results
+    }
+
+    pub async fn get_task(&self, task_id: &str) -> Option<Task> {
+        let mut con = self.connection().await.ok()?;
+        let task_json: Option<String> = con.hget(task_key(task_id), "data").await.ok()?;
+        task_json.and_then(|task_json| serde_json::from_str(&task_json).ok())
+    }
+
+    /// Atomically applies `update` to the stored task, retrying when another
+    /// writer changed the task in between, and returns the updated task.
+    pub async fn update_task<F>(&self, task_id: &str, mut update: F) -> Result<Task, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: FnMut(&mut Task),
+    {
+        let mut con = self.connection().await?;
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let (task_json, rev): (Option<String>, Option<String>) = redis::cmd("HMGET")
+                .arg(task_key(task_id))
+                .arg("data")
+                .arg("rev")
+                .query_async(&mut con)
+                .await?;
+            let (task_json, rev) = match (task_json, rev) {
+                (Some(task_json), Some(rev)) => (task_json, rev),
+                _ => return Err(format!("Task not found: {}", task_id).into()),
+            };
+
+            let mut task: Task = serde_json::from_str(&task_json)?;
+            let old_status = task.status.clone();
+            update(&mut task);
+            task.updated_at = Utc::now();
+
+            let updated: i32 = UPDATE_TASK_SCRIPT
+                .key(task_key(task_id))
+                .key(status_key(&old_status))
+                .key(status_key(&task.status))
+                .arg(task_id)
+                .arg(rev)
+                .arg(serde_json::to_string(&task)?)
+                .arg(task.status.as_str())
+                .arg(task.priority)
+                .invoke_async(&mut con)
+                .await?;
+            match updated {
+                1 => return Ok(task),
+                0 => debug!("Task {} changed concurrently, retrying update", task_id),
+                _ => return Err(format!("Task not found: {}", task_id).into()),
+            }
+        }
+        Err(format!("Too many concurrent updates on task: {}", task_id).into())
+    }
+
+    /// Marks a task completed by hand, as if it ran without output: an empty
+    /// result is stored, it leaves the queue, its dependents are unblocked, and
+    /// a permanent task is re-armed for its next run. Running tasks must be
+    /// cancelled first.
+    pub async fn validate_task(&self, task_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let task = self.get_task(task_id).await.ok_or_else(|| format!("Task not found: {}", task_id))?;
+        if task.status == TaskStatus::InProgress {
+            return Err(format!("Task {} is running", task_id).into());
+        }
+        // A permanent task satisfies its dependents once it has a result
+        let now = Utc::now();
+        let result_key = self.results.store(&TaskResult {
+            task_id: task.id.clone(),
+            action: task.action.clone(),
+            attempt: task.attempts,
+            output: String::new(),
+            model: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+            latency_ms: 0,
+            started_at: now,
+            finished_at: now,
+        }).await?;
+        let mut con = self.connection().await?;
+        let completed_count: u64 = con.get(COMPLETED_COUNT_KEY).await.unwrap_or(0);
+        let next_run = self.next_run(&task, completed_count)?;
+        let task = self.update_task(task_id, |task| {
+            task.result_key = Some(result_key.clone());
+            task.last_error = None;
+            task.set_status(TaskStatus::Completed);
+            if let Some(next_run) = &next_run {
+                task.rearm(next_run);
+            }
+        }).await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .zrem(QUEUE_KEY, task_id).ignore()
+            .zrem(SCHEDULED_KEY, task_id).ignore()
+            .zrem(SCHEDULED_AFTER_COUNT_KEY, task_id).ignore()
+            .lrem(DEAD_LETTER_KEY, 1, task_id).ignore();
+        for dependency_id in &task.depends_on {
+            pipe.srem(dependents_key(dependency_id), task_id).ignore();
+        }
+        if next_run.is_none() {
+            pipe.expire(task_key(task_id), self.finished_ttl.as_secs() as i64).ignore();
+        }
+        let _: () = pipe.query_async(&mut con).await?;
+        self.acknowledge_task(task_id, next_run.as_ref()).await?;
+        self.unblock_dependents(task_id).await
+    }
+
+    /// Runs a claimed task through its action handler and records the outcome.
+    pub async fn execute_task(&self, task: Task, actions: &ActionRegistry, tools: &ToolRegistry, templates: &TemplateLibrary, llm_client: &LLMClient, memory: Option<&MemoryStore>) {
+        // Retrying can't make an unknown action known
+        if !actions.contains(&task.action) {
+            error!("Task {} has an unknown action: {}", task.id, task.action);
+            if let Err(e) = self.give_up_task(&task, &format!("Unknown action: {}", task.action)).await {
+                error!("Failed to dead-letter task {}: {}", task.id, e);
+            }
+            return;
+        }
+        let ctx = ActionContext {
+            task_manager: self,
+            llm_client,
+            tools,
+            templates,
+            actions,
+            memory,
+            dependency_results: self.get_dependency_results(&task).await,
+        };
+        debug!("Executing task: {:?}", task);
+
+        let token = CancellationToken::new();
+        self.running.lock().unwrap().insert(task.id.clone(), token.clone());
+        let started = Instant::now();
+        let dispatch = actions.dispatch(&task, &ctx);
+        tokio::pin!(dispatch);
+        // Keep the lease alive while the action runs, however long its LLM calls take
+        let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + self.lease_heartbeat(), self.lease_heartbeat());
+        // Dropping the dispatch future closes the LLM connection, which stops the generation
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut dispatch => break Some(outcome),
+                _ = token.cancelled() => break None,
+                _ = heartbeat.tick() => {
+                    if let Err(e) = self.extend_lease(&task.id).await {
+                        error!("Failed to extend the lease of task {}: {}", task.id, e);
+                    }
+                }
+            }
+        };
+        self.running.lock().unwrap().remove(&task.id);
+
+        match outcome {
+            None => {
+                info!("Task {} cancelled", task.id);
+                if let Err(e) = self.give_up_task(&task, "Cancelled").await {
+                    error!("Failed to cancel task {}: {}", task.id, e);
+                }
+            }
+            Some(Ok(output)) => {
+                info!("Task processed with result: {}", output.text);
+                let result = TaskResult {
+                    task_id: task.id.clone(),
+                    action: task.action.clone(),
+                    attempt: task.attempts,
+                    output: output.text,
+                    model: output.model,
+                    prompt_tokens: output.prompt_tokens,
+                    completion_tokens: output.completion_tokens,
+                    latency_ms: started.elapsed().as_millis() as u64,
+                    started_at: task.started_at.unwrap_or_else(Utc::now),
+                    finished_at: Utc::now(),
+                };
+                if let Err(e) = self.complete_task(&task, &result).await {
+                    error!("Failed to complete task {}: {}", task.id, e);
+                }
+                if let (Some(memory), Some(kind)) = (memory, actions.memory_kind(&task.action)) {
+                    memory.remember(kind, &task.description, &result.output, Some(&task)).await;
+                }
+            }
+            Some(Err(e)) => {
+                // Another worker's trial call is in flight, so the call was never made
+                if let Some(open) = e.downcast_ref::<CircuitOpen>() {
+                    info!("Deferring task {}: {}", task.id, open);
+                    if let Err(e) = self.defer_task(&task, open.open_until).await {
+                        error!("Failed to defer task {}: {}", task.id, e);
+                    }
+                    return;
+                }
+                error!("Failed to execute task {}: {}", task.id, e);
+                if let Err(e) = self.fail_task(&task, &e.to_string()).await {
+                    error!("Failed to requeue task {}: {}", task.id, e);
+                }
+            }
+        }
+    }
+
+    /// How often the lease of a running task is extended: three times per
+    /// visibility timeout, so a missed beat doesn't let it expire.
+    fn lease_heartbeat(&self) -> Duration {
+        (self.visibility_timeout / 3).max(Duration::from_secs(1))
+    }
+
+    /// Pushes the lease deadline of an in-flight task one visibility timeout
+    /// ahead. Tasks no longer in flight are left alone.
+    async fn extend_lease(&self, task_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let lease_deadline = Utc::now().timestamp() + self.visibility_timeout.as_secs() as i64;
+        let _: () = redis::cmd("ZADD")
+            .arg(LEASES_KEY)
+            .arg("XX")
+            .arg(lease_deadline)
+            .arg(task_id)
+            .query_async(&mut con)
+            .await?;
         Ok(())
     }
 
-    pub async fn update_task_status(&self, task: &Task, new_status: TaskStatus) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut con = self.redis_client.lock().await.get_multiplexed_async_connection().await?;
-        let tasks_json: Vec<String> = con.lrange("tasks", 0, -1).await?;
-        
-        let mut updated_tasks = Vec::new();
-        for task_json in tasks_json {
-            let mut task_in_list: Task = serde_json::from_str(&task_json)?;
-            if task_in_list.description == task.description && task_in_list.action == task.action {
-                task_in_list.status = new_status.clone();
+    /// Cancels `task_id` if it is running in this process. Returns `false` otherwise.
+    pub fn cancel_task(&self, task_id: &str) -> bool {
+        match self.running.lock().unwrap().get(task_id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Puts a claimed task back on the schedule until `until` without running
+    /// it. The claim doesn't count as an attempt.
+    pub async fn defer_task(&self, task: &Task, until: DateTime<Utc>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.update_task(&task.id, |task| {
+            task.set_status(TaskStatus::Pending);
+            task.attempts = task.attempts.saturating_sub(1);
+            task.next_run_at = Some(until);
+        }).await?;
+        debug!("Deferring task {} until {}", task.id, until);
+        self.acknowledge_task(&task.id, Some(&NextRun::At(until))).await
+    }
+
+    /// Moves the highest priority queued task to the processing list under a
+    /// lease and marks it `InProgress`. Returns `None` if the queue stayed empty
+    /// for `CLAIM_TIMEOUT`.
+    pub async fn claim_next_task(&self) -> Result<Option<Task>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let started = Instant::now();
+        loop {
+            let lease_deadline = Utc::now().timestamp() + self.visibility_timeout.as_secs() as i64;
+            let task_id: Option<String> = CLAIM_TASK_SCRIPT
+                .key(QUEUE_KEY)
+                .key(PROCESSING_KEY)
+                .key(LEASES_KEY)
+                .arg(lease_deadline)
+                .invoke_async(&mut con)
+                .await?;
+            let task_id = match task_id {
+                Some(task_id) => task_id,
+                None if started.elapsed() >= CLAIM_TIMEOUT => return Ok(None),
+                None => {
+                    tokio::time::sleep(CLAIM_POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            match self.update_task(&task_id, |task| {
+                if task.status == TaskStatus::Pending {
+                    task.set_status(TaskStatus::InProgress);
+                }
+            }).await {
+                Ok(task) if task.status == TaskStatus::InProgress => return Ok(Some(task)),
+                Ok(task) => debug!("Skipping task {} with status {:?}", task_id, task.status),
+                Err(e) => error!("Failed to start task {}: {}", task_id, e),
             }
-            updated_tasks.push(serde_json::to_string(&task_in_list)?);
+            self.acknowledge_task(&task_id, None).await?;
         }
-        
-        con.del("tasks").await?;
-        for updated_task in updated_tasks {
-            con.rpush("tasks", updated_task).await?;
+    }
+
+    /// Stores the result, marks the task `Completed` and releases its lease.
+    /// Permanent tasks are re-armed according to their recurrence instead.
+    async fn complete_task(&self, task: &Task, result: &TaskResult) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let result_key = self.results.store(result).await?;
+        let mut con = self.connection().await?;
+        let completed_count: u64 = con.incr(COMPLETED_COUNT_KEY, 1).await?;
+        let next_run = self.next_run(task, completed_count)?;
+
+        let task = self.update_task(&task.id, |task| {
+            task.result_key = Some(result_key.clone());
+            task.last_error = None;
+            task.set_status(TaskStatus::Completed);
+            if let Some(next_run) = &next_run {
+                task.rearm(next_run);
+            }
+        }).await?;
+        info!("Task completed and status updated: {:?}", task);
+        self.acknowledge_task(&task.id, next_run.as_ref()).await?;
+        self.unblock_dependents(&task.id).await?;
+        if next_run.is_none() {
+            let _: () = con.expire(task_key(&task.id), self.finished_ttl.as_secs() as i64).await?;
         }
         Ok(())
     }
 
-    pub async fn execute_tasks(&self, llm_client: &LLMClient) {
-        let mut con = self.redis_client.lock().await.get_multiplexed_async_connection().await.unwrap();
-        while let Some(task_json) = con.lpop::<_, Option<String>>("tasks", None).await.unwrap() {
-            let task: Task = serde_json::from_str(&task_json).unwrap();
-            if task.status == TaskStatus::Pending {
-                debug!("Executing task: {:?}", task);
-
-                match llm_client.process_task(&task).await {
-                    Ok(result) => {
-                        info!("Task processed with result: {}", result);
-                        // Store result in Redis
-                        let _: () = con.set(&task.description, result).await.unwrap();
-                        match self.update_task_status(&task, TaskStatus::Completed).await {
-                            Ok(_) => info!("Task completed and status updated: {:?}", task),
-                            Err(e) => error!("Failed to update task status: {:?}", e),
-                        }
+    /// Schedules a retry of a failed task with exponential backoff. Once its
+    /// retry policy is exhausted the task is marked `Failed` and moved to the
+    /// dead-letter list, except permanent tasks, which wait for their next run.
+    async fn fail_task(&self, task: &Task, error: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let policy = task.retry_policy.clone().unwrap_or_else(|| self.retry_policy.clone());
+        if task.attempts < policy.max_attempts {
+            // A policy stored before policies were validated may not fit in a date
+            let Some(retry_at) = Utc::now().checked_add_signed(policy.backoff(task.attempts)) else {
+                error!("Retry delay of task {} is out of range, giving up", task.id);
+                return self.give_up_task(task, error).await;
+            };
+            let next_run = NextRun::At(retry_at);
+            self.update_task(&task.id, |task| {
+                task.last_error = Some(error.to_string());
+                task.set_status(TaskStatus::Pending);
+                task.next_run_at = Some(retry_at);
+            }).await?;
+            info!("Retrying task {} at {} (attempt {} of {})", task.id, retry_at, task.attempts + 1, policy.max_attempts);
+            return self.acknowledge_task(&task.id, Some(&next_run)).await;
+        }
+
+        error!("Giving up on task {} after {} attempts", task.id, task.attempts);
+        self.give_up_task(task, error).await
+    }
+
+    /// Marks a task `Failed` and moves it to the dead-letter list without
+    /// retrying it. Permanent tasks wait for their next run instead.
+    async fn give_up_task(&self, task: &Task, error: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let completed_count: u64 = con.get(COMPLETED_COUNT_KEY).await.unwrap_or(0);
+        if let Some(next_run) = self.next_run(task, completed_count)? {
+            self.update_task(&task.id, |task| {
+                task.last_error = Some(error.to_string());
+                task.rearm(&next_run);
+            }).await?;
+            return self.acknowledge_task(&task.id, Some(&next_run)).await;
+        }
+
+        self.update_task(&task.id, |task| {
+            task.last_error = Some(error.to_string());
+            task.set_status(TaskStatus::Failed);
+        }).await?;
+        let _: () = redis::pipe()
+            .atomic()
+            .lrem(PROCESSING_KEY, 1, &task.id).ignore()
+            .zrem(LEASES_KEY, &task.id).ignore()
+            .rpush(DEAD_LETTER_KEY, &task.id).ignore()
+            .expire(task_key(&task.id), self.finished_ttl.as_secs() as i64).ignore()
+            .query_async(&mut con)
+            .await?;
+        self.fail_dependents(&task.id).await
+    }
+
+    /// Fails the one-shot tasks blocked on a task that failed for good, and
+    /// their own dependents in turn, since they can never run. Blocked
+    /// permanent tasks keep waiting, with the failure in `last_error`, until
+    /// the failed task is validated by hand or expires with its dependents set.
+    async fn fail_dependents(&self, task_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let mut failed = vec![task_id.to_string()];
+        while let Some(failed_id) = failed.pop() {
+            let dependents: Vec<String> = con.smembers(dependents_key(&failed_id)).await?;
+            for dependent in dependents {
+                let error = format!("Dependency {} failed", failed_id);
+                let mut dead = false;
+                let updated = self.update_task(&dependent, |task| {
+                    dead = false;
+                    if task.status != TaskStatus::Blocked {
+                        return;
+                    }
+                    task.last_error = Some(error.clone());
+                    dead = !task.is_permanent;
+                    if dead {
+                        task.set_status(TaskStatus::Failed);
                     }
-                    Err(e) => error!("Failed to process task with LLM: {:?}", e),
+                }).await;
+                if let Err(e) = updated {
+                    debug!("Skipping dependent {} of failed task {}: {}", dependent, failed_id, e);
+                    continue;
+                }
+                if dead {
+                    info!("Failing task {}: {}", dependent, error);
+                    let _: () = redis::pipe()
+                        .atomic()
+                        .rpush(DEAD_LETTER_KEY, &dependent).ignore()
+                        .expire(task_key(&dependent), self.finished_ttl.as_secs() as i64).ignore()
+                        .query_async(&mut con)
+                        .await?;
+                    let _: () = con.srem(dependents_key(&failed_id), &dependent).await?;
+                    failed.push(dependent);
+                }
+            }
+            let _: () = con.expire(dependents_key(&failed_id), self.finished_ttl.as_secs() as i64).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the tasks in the dead-letter list, oldest first.
+    pub async fn get_dead_letter_tasks(&self) -> Result<Vec<Task>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let task_ids: Vec<String> = con.lrange(DEAD_LETTER_KEY, 0, -1).await?;
+        let mut tasks = Vec::new();
+        for task_id in task_ids {
+            if let Some(task) = self.get_task(&task_id).await {
+                tasks.push(task);
+            }
+        }
+        Ok(tasks)
+    }
+
+    /// Resets a dead-lettered task's attempts and puts it back in the queue.
+    pub async fn replay_dead_letter_task(&self, task_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let task = self.get_task(task_id).await.ok_or_else(|| format!("Task not found: {}", task_id))?;
+        if task.status != TaskStatus::Failed {
+            return Err(format!("Task {} is not failed but {:?}", task_id, task.status).into());
+        }
+        let unsatisfied = self.check_dependencies(&task).await?;
+        if !unsatisfied.is_empty() {
+            return Err(format!("Task {} still depends on unfinished tasks: {}", task_id, unsatisfied.join(", ")).into());
+        }
+        let task = self.update_task(task_id, |task| {
+            task.set_status(TaskStatus::Pending);
+            task.attempts = 0;
+            task.next_run_at = None;
+        }).await?;
+
+        let mut con = self.connection().await?;
+        let _: () = con.persist(task_key(task_id)).await?;
+        let replayed: i32 = REPLAY_TASK_SCRIPT
+            .key(DEAD_LETTER_KEY)
+            .key(QUEUE_KEY)
+            .arg(task_id)
+            .arg(queue_score(task.priority, Utc::now()))
+            .invoke_async(&mut con)
+            .await?;
+        if replayed == 0 {
+            return Err(format!("Task {} is not in the dead-letter list", task_id).into());
+        }
+        info!("Replaying dead-lettered task {}", task_id);
+        Ok(())
+    }
+
+    /// Next run of a permanent task, `None` for one-shot tasks.
+    fn next_run(&self, task: &Task, completed_count: u64) -> Result<Option<NextRun>, Box<dyn std::error::Error + Send + Sync>> {
+        if !task.is_permanent {
+            return Ok(None);
+        }
+        let recurrence = task.recurrence.clone()
+            .unwrap_or(Recurrence::Interval { seconds: DEFAULT_RECURRENCE_SECS });
+        recurrence.next_run(completed_count).map(Some)
+    }
+
+    /// Moves re-armed permanent tasks that are due back to the queue.
+    pub async fn promote_due_tasks(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let completed_count: u64 = con.get(COMPLETED_COUNT_KEY).await.unwrap_or(0);
+        let mut promoted = 0;
+        for (schedule, max_score) in [(SCHEDULED_KEY, Utc::now().timestamp()), (SCHEDULED_AFTER_COUNT_KEY, completed_count as i64)] {
+            let count: usize = PROMOTE_TASKS_SCRIPT
+                .key(schedule)
+                .key(QUEUE_KEY)
+                .arg(max_score)
+                .arg(Utc::now().timestamp())
+                .arg(PRIORITY_AGING_SECS)
+                .invoke_async(&mut con)
+                .await?;
+            promoted += count;
+        }
+        if promoted > 0 {
+            debug!("Promoted {} due permanent tasks", promoted);
+        }
+        Ok(promoted)
+    }
+
+    /// Removes the task from the processing list and drops its lease, adding
+    /// it to the matching schedule in the same transaction when re-armed.
+    async fn acknowledge_task(&self, task_id: &str, next_run: Option<&NextRun>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .lrem(PROCESSING_KEY, 1, task_id).ignore()
+            .zrem(LEASES_KEY, task_id).ignore();
+        match next_run {
+            Some(NextRun::At(at)) => {
+                pipe.zadd(SCHEDULED_KEY, task_id, at.timestamp()).ignore();
+            }
+            Some(NextRun::AfterCompletions(count)) => {
+                pipe.zadd(SCHEDULED_AFTER_COUNT_KEY, task_id, *count).ignore();
+            }
+            None => {}
+        }
+        let _: () = pipe.query_async(&mut con).await?;
+        Ok(())
+    }
+
+    /// Moves a task from the processing list back to the queue, scored as if
+    /// it was queued when it was created so it runs ahead of newer work.
+    /// Returns `false` if the task was no longer being processed.
+    async fn requeue_task(&self, task: &Task) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let requeued: i32 = REQUEUE_TASK_SCRIPT
+            .key(PROCESSING_KEY)
+            .key(LEASES_KEY)
+            .key(QUEUE_KEY)
+            .arg(&task.id)
+            .arg(queue_score(task.priority, task.created_at))
+            .invoke_async(&mut con)
+            .await?;
+        Ok(requeued == 1)
+    }
+
+    /// Puts an in-flight task back at the front of the queue, resetting it to
+    /// `Pending` unless it already completed.
+    async fn reclaim_task(&self, task_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match self.update_task(task_id, |task| {
+            if task.status == TaskStatus::InProgress {
+                task.set_status(TaskStatus::Pending);
+            }
+        }).await {
+            Ok(task) => {
+                if self.requeue_task(&task).await? {
+                    info!("Reclaimed in-flight task {}", task_id);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // The task hash is gone, there is nothing left to run.
+                self.acknowledge_task(task_id, None).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Requeues every task left in the processing list by a previous run.
+    /// Must be called before any worker starts claiming tasks.
+    pub async fn recover_in_flight_tasks(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let task_ids: Vec<String> = con.lrange(PROCESSING_KEY, 0, -1).await?;
+        for task_id in &task_ids {
+            if let Err(e) = self.reclaim_task(task_id).await {
+                error!("Failed to recover task {}: {}", task_id, e);
+            }
+        }
+        Ok(task_ids.len())
+    }
+
+    /// Requeues in-flight tasks whose lease expired without being acknowledged.
+    /// Tasks still running in this process are left to finish.
+    pub async fn reclaim_expired_tasks(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let mut task_ids: Vec<String> = con.zrangebyscore(LEASES_KEY, "-inf", Utc::now().timestamp()).await?;
+        {
+            let running = self.running.lock().unwrap();
+            task_ids.retain(|task_id| !running.contains_key(task_id));
+        }
+        for task_id in &task_ids {
+            if let Err(e) = self.reclaim_task(task_id).await {
+                error!("Failed to reclaim task {}: {}", task_id, e);
+            }
+        }
+        Ok(task_ids.len())
+    }
+
+    /// Drops the IDs of expired finished tasks from the status sets and the
+    /// dead-letter list. Returns the number of tasks dropped.
+    pub async fn prune_expired_tasks(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let mut pruned = 0;
+        for status in [TaskStatus::Completed, TaskStatus::Failed] {
+            let task_ids: Vec<String> = con.smembers(status_key(&status)).await?;
+            if task_ids.is_empty() {
+                continue;
+            }
+            let mut pipe = redis::pipe();
+            for task_id in &task_ids {
+                pipe.exists(task_key(task_id));
+            }
+            let exists: Vec<bool> = pipe.query_async(&mut con).await?;
+            let expired: Vec<&String> = task_ids.iter().zip(exists)
+                .filter(|(_, exists)| !exists)
+                .map(|(task_id, _)| task_id)
+                .collect();
+            if expired.is_empty() {
+                continue;
+            }
+            let mut pipe = redis::pipe();
+            pipe.atomic().srem(status_key(&status), &expired).ignore();
+            if status == TaskStatus::Failed {
+                for task_id in &expired {
+                    pipe.lrem(DEAD_LETTER_KEY, 1, *task_id).ignore();
                 }
             }
+            let _: () = pipe.query_async(&mut con).await?;
+            pruned += expired.len();
         }
+        Ok(pruned)
     }
 
     pub async fn check_redis_connection(&self) -> Result<(), redis::RedisError> {
-        let mut con = self.redis_client.lock().await.get_multiplexed_async_connection().await?;
+        let mut con = self.connection().await?;
         let _: () = con.set_ex("redis_connection_check", "OK", 10).await?;
         Ok(())
     }
 
-    pub async fn get_tasks(&self) -> Vec<Task> {
-        let mut con = self.redis_client.lock().await.get_multiplexed_async_connection().await.unwrap();
-        let tasks_json: Vec<String> = con.lrange("tasks", 0, -1).await.unwrap_or_default();
-        
-        let mut tasks = Vec::new();
-        for task_json in tasks_json {
-            if let Ok(task) = serde_json::from_str::<Task>(&task_json) {
-                tasks.push(task);
+    /// Returns the tasks in the given status, oldest first.
+    pub async fn get_tasks_by_status(&self, status: &TaskStatus) -> Vec<Task> {
+        let mut con = match self.connection().await {
+            Ok(con) => con,
+            Err(e) => {
+                error!("Failed to get Redis connection: {:?}", e);
+                return vec![];
             }
+        };
+        let task_ids: Vec<String> = con.smembers(status_key(status)).await.unwrap_or_default();
+        if task_ids.is_empty() {
+            return vec![];
         }
+
+        let mut pipe = redis::pipe();
+        for task_id in &task_ids {
+            pipe.hget(task_key(task_id), "data");
+        }
+        let tasks_json: Vec<Option<String>> = pipe.query_async(&mut con).await.unwrap_or_default();
+        debug!("Retrieved {} {} tasks from Redis", tasks_json.len(), status.as_str());
+
+        let mut tasks: Vec<Task> = tasks_json.into_iter()
+            .flatten()
+            .filter_map(|task_json| serde_json::from_str::<Task>(&task_json).ok())
+            .collect();
+        tasks.sort_by_key(|task| task.created_at);
+        tasks
+    }
+
+    /// Returns every task that has not completed yet.
+    pub async fn get_tasks(&self) -> Vec<Task> {
+        let mut tasks = self.get_tasks_by_status(&TaskStatus::Pending).await;
+        tasks.extend(self.get_tasks_by_status(&TaskStatus::Blocked).await);
+        tasks.extend(self.get_tasks_by_status(&TaskStatus::InProgress).await);
+        tasks.sort_by_key(|task| task.created_at);
         tasks
     }
 
+    /// Number of tasks in every status.
+    pub async fn count_tasks_by_status(&self) -> Result<Vec<(TaskStatus, usize)>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        let mut pipe = redis::pipe();
+        for status in &TaskStatus::ALL {
+            pipe.scard(status_key(status));
+        }
+        let counts: Vec<usize> = pipe.query_async(&mut con).await?;
+        Ok(TaskStatus::ALL.into_iter().zip(counts).collect())
+    }
+
     pub async fn get_completed_tasks(&self) -> Vec<Task> {
-        match self.redis_client.lock().await.get_multiplexed_async_connection().await {
-            Ok(mut con) => {
-                let tasks_json: Vec<String> = con.lrange("completed_tasks", 0, -1).await.unwrap();
-                debug!("Retrieved completed tasks JSON from Redis: {:?}", tasks_json);
-                let tasks: Vec<Task> = tasks_json.into_iter().map(|task_json| {
-                    let task: Task = serde_json::from_str(&task_json).unwrap();
-                    task
-                }).collect();
-                debug!("Deserialized completed tasks: {:?}", tasks);
-                tasks
-            },
-            Err(e) => {
-                error!("Failed to get Redis connection: {:?}", e);
-                vec![]
-            },
+        self.get_tasks_by_status(&TaskStatus::Completed).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_generated_priorities() {
+        assert_eq!(Task::new("a", "b", false).with_generated_priority(1000).priority, 0);
+        assert_eq!(Task::new("a", "b", false).with_generated_priority(-3).priority, -3);
+        assert_eq!(Task::new("a", "b", false).with_generated_priority(i64::MIN).priority, MIN_GENERATED_PRIORITY);
+    }
+
+    #[test]
+    fn rejects_out_of_range_intervals() {
+        assert!(Recurrence::Interval { seconds: 0 }.validate().is_err());
+        assert!(Recurrence::Interval { seconds: MAX_RECURRENCE_SECS }.validate().is_ok());
+        assert!(Recurrence::Interval { seconds: 100_000_000_000_000 }.validate().is_err());
+    }
+
+    #[test]
+    fn next_run_errs_instead_of_overflowing() {
+        assert!(Recurrence::Interval { seconds: 60 }.next_run(0).is_ok());
+        assert!(Recurrence::Interval { seconds: 100_000_000_000_000 }.next_run(0).is_err());
+        assert!(Recurrence::Interval { seconds: u64::MAX }.next_run(0).is_err());
+        assert!(Recurrence::AfterTasks { count: u64::MAX }.next_run(1).is_err());
+    }
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay_secs: 5,
+            max_delay_secs: 60,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn validates_retry_policies() {
+        assert!(policy(0.2).validate().is_ok());
+        assert!(policy(1.5).validate().is_err());
+        assert!(policy(f64::NAN).validate().is_err());
+        assert!(RetryPolicy { max_delay_secs: u64::MAX, ..policy(0.0) }.validate().is_err());
+        assert!(RetryPolicy { base_delay_secs: 120, ..policy(0.0) }.validate().is_err());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = policy(0.0);
+        let delays: Vec<i64> = (1..=6).map(|attempts| policy.backoff(attempts).num_seconds()).collect();
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60]);
+        assert_eq!(policy.backoff(0).num_seconds(), 5);
+        assert_eq!(policy.backoff(u32::MAX).num_seconds(), 60);
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_its_fraction() {
+        let policy = policy(0.2);
+        for _ in 0..100 {
+            let delay = policy.backoff(2).num_milliseconds();
+            assert!((8_000..=12_000).contains(&delay), "{} ms", delay);
+        }
+    }
+
+    #[test]
+    fn backoff_clamps_the_jitter() {
+        let policy = policy(5.0);
+        for _ in 0..100 {
+            let delay = policy.backoff(1).num_milliseconds();
+            assert!((0..=10_000).contains(&delay), "{} ms", delay);
         }
     }
 }
@@ -0,0 +1,293 @@
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use crate::actions::{read_recent_logs, RECENT_LOG_LINES};
+use crate::prompt::{estimate_tokens, newest_lines_within};
+use crate::task_manager::{Task, TaskManager};
+
+/// Variables a template may use, as `{{name}}`.
+pub const TEMPLATE_VARIABLES: [&str; 5] = ["task", "action", "recent_logs", "queue_stats", "now"];
+
+/// Template settings, read from the `[prompts]` section of `config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TemplateConfig {
+    /// Directory holding the `<name>.v<version>.txt` template files.
+    pub dir: String,
+    /// Versions to use instead of the latest, by template name.
+    pub pinned: HashMap<String, u32>,
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        TemplateConfig {
+            dir: "prompts".to_string(),
+            pinned: HashMap::new(),
+        }
+    }
+}
+
+/// One version of a prompt template.
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptTemplate {
+    /// Name of the action the template is rendered for.
+    pub name: String,
+    pub version: u32,
+    pub text: String,
+    pub modified_at: Option<DateTime<Utc>>,
+}
+
+impl PromptTemplate {
+    /// Names of the `{{variables}}` used in the template, in order of appearance.
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables = Vec::new();
+        let mut rest = self.text.as_str();
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            let variable = rest[start + 2..start + end].trim().to_string();
+            if !variables.contains(&variable) {
+                variables.push(variable);
+            }
+            rest = &rest[start + end + 2..];
+        }
+        variables
+    }
+}
+
+/// The versions of a template, as listed by `/prompts`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TemplateSummary {
+    pub name: String,
+    pub versions: Vec<u32>,
+    /// Version rendered for the action: the pinned one, or the latest.
+    pub active_version: u32,
+    pub variables: Vec<String>,
+}
+
+/// Splits a template file name into its name and version. Files without a
+/// `.v<version>` suffix are version 1.
+fn parse_file_name(file_name: &str) -> Option<(String, u32)> {
+    let stem = file_name.strip_suffix(".txt")?;
+    match stem.rsplit_once(".v") {
+        Some((name, version)) if !name.is_empty() => match version.parse() {
+            Ok(version) => Some((name.to_string(), version)),
+            Err(_) => Some((stem.to_string(), 1)),
+        },
+        _ => Some((stem.to_string(), 1)),
+    }
+}
+
+/// Prompt templates for actions, loaded from text files so prompts can be
+/// changed without recompiling. Shared by every clone.
+#[derive(Debug, Clone)]
+pub struct TemplateLibrary {
+    config: TemplateConfig,
+    /// Versions of every template, oldest first.
+    templates: Arc<RwLock<BTreeMap<String, Vec<PromptTemplate>>>>,
+}
+
+impl Default for TemplateLibrary {
+    /// A library of the default directory, empty until reloaded.
+    fn default() -> Self {
+        TemplateLibrary {
+            config: TemplateConfig::default(),
+            templates: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+}
+
+impl TemplateLibrary {
+    pub fn new(config: TemplateConfig) -> Self {
+        let library = TemplateLibrary {
+            config,
+            ..TemplateLibrary::default()
+        };
+        match library.reload() {
+            Ok(count) => info!("Loaded {} prompt templates from {}", count, library.config.dir),
+            Err(e) => warn!("Failed to load prompt templates from {}: {}", library.config.dir, e),
+        }
+        library
+    }
+
+    /// Reads the template directory again and returns the number of template
+    /// files. A missing directory means no templates.
+    pub fn reload(&self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let dir = Path::new(&self.config.dir);
+        let mut templates: BTreeMap<String, Vec<PromptTemplate>> = BTreeMap::new();
+        let mut count = 0;
+        if dir.is_dir() {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let Some((name, version)) = entry.file_name().to_str().and_then(parse_file_name) else {
+                    continue;
+                };
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                templates.entry(name.clone()).or_default().push(PromptTemplate {
+                    name,
+                    version,
+                    text: std::fs::read_to_string(entry.path())?,
+                    modified_at: metadata.modified().ok().map(DateTime::<Utc>::from),
+                });
+                count += 1;
+            }
+        }
+        for versions in templates.values_mut() {
+            versions.sort_by_key(|template| template.version);
+        }
+        debug!("Read {} prompt templates from {}", count, self.config.dir);
+        *self.templates.write().unwrap() = templates;
+        Ok(count)
+    }
+
+    fn active_version(&self, name: &str, versions: &[PromptTemplate]) -> Option<u32> {
+        match self.config.pinned.get(name) {
+            Some(pinned) if versions.iter().any(|template| template.version == *pinned) => Some(*pinned),
+            Some(pinned) => {
+                warn!("Pinned version {} of prompt template {} doesn't exist, using the latest", pinned, name);
+                versions.last().map(|template| template.version)
+            }
+            None => versions.last().map(|template| template.version),
+        }
+    }
+
+    pub fn list(&self) -> Vec<TemplateSummary> {
+        let templates = self.templates.read().unwrap();
+        templates.iter()
+            .filter_map(|(name, versions)| {
+                let active_version = self.active_version(name, versions)?;
+                let active = versions.iter().find(|template| template.version == active_version)?;
+                Some(TemplateSummary {
+                    name: name.clone(),
+                    versions: versions.iter().map(|template| template.version).collect(),
+                    active_version,
+                    variables: active.variables(),
+                })
+            })
+            .collect()
+    }
+
+    /// The given version of a template, or its active version.
+    pub fn get(&self, name: &str, version: Option<u32>) -> Option<PromptTemplate> {
+        let templates = self.templates.read().unwrap();
+        let versions = templates.get(name)?;
+        let version = match version {
+            Some(version) => version,
+            None => self.active_version(name, versions)?,
+        };
+        versions.iter().find(|template| template.version == version).cloned()
+    }
+
+    /// Fills in the variables of `template` for `task`. Unknown variables are
+    /// left as is. The oldest of the `recent_logs` lines are cut so the
+    /// rendered prompt fits in `budget` tokens.
+    pub async fn render(&self, template: &PromptTemplate, task: &Task, task_manager: &TaskManager, budget: usize) -> String {
+        let mut values = HashMap::new();
+        let mut logs = None;
+        for variable in template.variables() {
+            let value = match variable.as_str() {
+                "task" => task.description.clone(),
+                "action" => task.action.clone(),
+                // Filled in last, with what the rest of the prompt leaves
+                "recent_logs" => {
+                    logs = Some(read_recent_logs(RECENT_LOG_LINES));
+                    String::new()
+                }
+                "queue_stats" => match task_manager.count_tasks_by_status().await {
+                    Ok(counts) => counts.iter()
+                        .map(|(status, count)| format!("{}: {}", status.as_str(), count))
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                    Err(e) => format!("(queue stats unavailable: {})", e),
+                },
+                "now" => Utc::now().to_rfc3339(),
+                _ => {
+                    warn!("Unknown variable {} in prompt template {} v{}, expected one of {:?}",
+                        variable, template.name, template.version, TEMPLATE_VARIABLES);
+                    continue;
+                }
+            };
+            values.insert(variable, value);
+        }
+
+        if let Some(logs) = logs {
+            let value = match logs {
+                Ok(lines) => {
+                    let remaining = budget.saturating_sub(estimate_tokens(&substitute(&template.text, &values)));
+                    let kept = newest_lines_within(&lines, remaining);
+                    if kept.len() < lines.len() {
+                        warn!("Cut {} of {} log lines of prompt template {} v{} to fit the context window",
+                            lines.len() - kept.len(), lines.len(), template.name, template.version);
+                    }
+                    kept.join("\n")
+                }
+                Err(e) => format!("(logs unavailable: {})", e),
+            };
+            values.insert("recent_logs".to_string(), value);
+        }
+        substitute(&template.text, &values)
+    }
+}
+
+/// `text` with every `{{name}}` in `values` replaced by its value.
+fn substitute(text: &str, values: &HashMap<String, String>) -> String {
+    let mut rendered = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        match values.get(rest[start + 2..start + end].trim()) {
+            Some(value) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(text: &str) -> PromptTemplate {
+        PromptTemplate {
+            name: "comment_last_logs".to_string(),
+            version: 1,
+            text: text.to_string(),
+            modified_at: None,
+        }
+    }
+
+    #[test]
+    fn parses_template_file_names() {
+        assert_eq!(parse_file_name("comment_last_logs.v2.txt"), Some(("comment_last_logs".to_string(), 2)));
+        assert_eq!(parse_file_name("comment_last_logs.txt"), Some(("comment_last_logs".to_string(), 1)));
+        assert_eq!(parse_file_name("report.vnext.txt"), Some(("report.vnext".to_string(), 1)));
+        assert_eq!(parse_file_name(".v3.txt"), Some((".v3".to_string(), 1)));
+        assert_eq!(parse_file_name("notes.md"), None);
+    }
+
+    #[test]
+    fn lists_variables_once_in_order() {
+        let template = template("{{ task }} at {{now}}: {{task}} {{recent_logs}} {{unclosed");
+        assert_eq!(template.variables(), ["task", "now", "recent_logs"]);
+    }
+
+    #[test]
+    fn substitutes_known_variables_and_keeps_the_others() {
+        let values = HashMap::from([("task".to_string(), "Check logs".to_string())]);
+        assert_eq!(substitute("Do {{ task }} then {{task}}, see {{other}}", &values), "Do Check logs then Check logs, see {{other}}");
+        assert_eq!(substitute("open {{task", &values), "open {{task");
+    }
+}
@@ -0,0 +1,316 @@
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use crate::actions::read_recent_logs;
+use crate::llm_client::{ChatMessage, LLMClient, LLMResponse};
+use crate::task_manager::{Task, TaskManager, TaskStatus};
+
+/// Default number of model turns in a tool loop before it is cut short.
+pub const DEFAULT_MAX_TOOL_ROUNDS: usize = 5;
+
+/// Most log lines `read_recent_logs` hands back in one call.
+const MAX_LOG_LINES: usize = 200;
+
+/// Tasks one tool loop may queue through `add_task`.
+const MAX_QUEUED_TASKS: usize = 5;
+
+/// A tool as described to the model.
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments.
+    pub parameters: Value,
+}
+
+/// A tool invocation requested by the model, in Ollama's wire format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+/// What a tool may use while it runs.
+pub struct ToolContext<'a> {
+    pub task_manager: &'a TaskManager,
+    pub llm_client: &'a LLMClient,
+}
+
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn definition(&self) -> ToolDefinition;
+
+    /// Runs the tool and returns its result as text for the model.
+    async fn call(&self, arguments: &Value, ctx: &ToolContext<'_>) -> Result<String, Box<dyn Error + Send + Sync>>;
+}
+
+/// Maps tool names to the capabilities the model may invoke.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: HashMap<String, Arc<dyn Tool>>,
+    max_rounds: usize,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry {
+            tools: HashMap::new(),
+            max_rounds: DEFAULT_MAX_TOOL_ROUNDS,
+        }
+    }
+
+    /// Registry with the built-in tools. `actions` are the actions `add_task` may queue.
+    pub fn with_builtin_tools(actions: Vec<String>) -> Self {
+        let mut registry = ToolRegistry::new();
+        registry.register(ListTasks);
+        registry.register(AddTask { actions });
+        registry.register(GetTaskResult);
+        registry.register(ReadRecentLogs);
+        registry.register(GetStatus);
+        registry
+    }
+
+    pub fn with_max_rounds(mut self, max_rounds: usize) -> Self {
+        self.max_rounds = max_rounds.max(1);
+        self
+    }
+
+    pub fn register<T: Tool + 'static>(&mut self, tool: T) {
+        self.tools.insert(tool.definition().name, Arc::new(tool));
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        let mut definitions: Vec<ToolDefinition> = self.tools.values().map(|tool| tool.definition()).collect();
+        definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        definitions
+    }
+
+    /// Runs `call`. Failures are returned as text so the model can react to them.
+    pub async fn call(&self, call: &ToolCall, ctx: &ToolContext<'_>) -> String {
+        let Some(tool) = self.tools.get(&call.function.name) else {
+            return format!("Error: unknown tool {}", call.function.name);
+        };
+        debug!("Calling tool {} with {}", call.function.name, call.function.arguments);
+        match tool.call(&call.function.arguments, ctx).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Tool {} failed: {}", call.function.name, e);
+                format!("Error: {}", e)
+            }
+        }
+    }
+
+    /// Chats with the model, running the tools it calls and feeding their
+    /// results back until it answers without calling any, for at most
+    /// `max_rounds` turns. At most `MAX_QUEUED_TASKS` calls of `add_task` are run.
+    pub async fn run(&self, mut messages: Vec<ChatMessage>, task: Option<&Task>, ctx: &ToolContext<'_>) -> Result<LLMResponse, Box<dyn Error + Send + Sync>> {
+        let definitions = self.definitions();
+        let mut queued_tasks = 0;
+        for _ in 0..self.max_rounds {
+            let response = ctx.llm_client.chat_tools(&messages, task, &definitions).await?;
+            if response.tool_calls.is_empty() {
+                return Ok(response);
+            }
+
+            messages.push(ChatMessage::assistant(&response.text).with_tool_calls(response.tool_calls.clone()));
+            for call in &response.tool_calls {
+                let result = if call.function.name == ADD_TASK_TOOL && queued_tasks >= MAX_QUEUED_TASKS {
+                    format!("Error: at most {} tasks may be queued at a time", MAX_QUEUED_TASKS)
+                } else {
+                    if call.function.name == ADD_TASK_TOOL {
+                        queued_tasks += 1;
+                    }
+                    self.call(call, ctx).await
+                };
+                messages.push(ChatMessage::tool(&call.function.name, &result));
+            }
+        }
+        Err(format!("LLM still calling tools after {} rounds", self.max_rounds).into())
+    }
+}
+
+impl std::fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ToolRegistry")
+            .field("tools", &self.definitions().iter().map(|tool| tool.name.clone()).collect::<Vec<String>>())
+            .field("max_rounds", &self.max_rounds)
+            .finish()
+    }
+}
+
+fn parse_status(status: &str) -> Option<TaskStatus> {
+    TaskStatus::ALL.into_iter().find(|candidate| candidate.as_str() == status)
+}
+
+/// Lists the tasks with a given status, or the queued ones.
+struct ListTasks;
+
+#[async_trait]
+impl Tool for ListTasks {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "list_tasks".to_string(),
+            description: "List tasks with their ID, description, action, status and priority. \
+                          Without a status, lists the pending, blocked and in progress tasks.".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "status": { "type": "string", "enum": ["pending", "blocked", "in_progress", "completed", "failed"] }
+                }
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: &Value, ctx: &ToolContext<'_>) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let tasks = match arguments.get("status").and_then(Value::as_str) {
+            Some(status) => {
+                let status = parse_status(status).ok_or_else(|| format!("Unknown status: {}", status))?;
+                ctx.task_manager.get_tasks_by_status(&status).await
+            }
+            None => ctx.task_manager.get_tasks().await,
+        };
+        let tasks: Vec<Value> = tasks.iter()
+            .map(|task| json!({
+                "id": task.id,
+                "description": task.description,
+                "action": task.action,
+                "status": task.status.as_str(),
+                "priority": task.priority,
+            }))
+            .collect();
+        Ok(serde_json::to_string(&tasks)?)
+    }
+}
+
+/// Name of the `AddTask` tool.
+const ADD_TASK_TOOL: &str = "add_task";
+
+/// Queues a new task for one of the registered actions.
+struct AddTask {
+    actions: Vec<String>,
+}
+
+#[async_trait]
+impl Tool for AddTask {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: ADD_TASK_TOOL.to_string(),
+            description: "Queue a new task and return its ID.".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "description": { "type": "string" },
+                    "action": { "type": "string", "enum": self.actions },
+                    "priority": { "type": "integer", "description": "Higher runs first, from -10 to 0, default 0" },
+                    "tags": { "type": "array", "items": { "type": "string" }, "description": "Labels choosing the model the task runs on" }
+                },
+                "required": ["description", "action"]
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: &Value, ctx: &ToolContext<'_>) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let description = arguments.get("description").and_then(Value::as_str).ok_or("Missing description")?;
+        let action = arguments.get("action").and_then(Value::as_str).ok_or("Missing action")?;
+        if !self.actions.iter().any(|known| known == action) {
+            return Err(format!("Unknown action: {}", action).into());
+        }
+        let priority = arguments.get("priority").and_then(Value::as_i64).unwrap_or(0);
+        let tags = arguments.get("tags").and_then(Value::as_array)
+            .map(|tags| tags.iter().filter_map(Value::as_str).map(str::to_string).collect())
+            .unwrap_or_default();
+
+        let task = Task::new(description, action, false).with_generated_priority(priority).with_tags(tags);
+        let task_id = ctx.task_manager.add_task(task).await?;
+        Ok(format!("Task queued with ID {}", task_id))
+    }
+}
+
+/// Reads the latest result of a task.
+struct GetTaskResult;
+
+#[async_trait]
+impl Tool for GetTaskResult {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "get_task_result".to_string(),
+            description: "Read the output of the last run of a task.".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "task_id": { "type": "string" }
+                },
+                "required": ["task_id"]
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: &Value, ctx: &ToolContext<'_>) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let task_id = arguments.get("task_id").and_then(Value::as_str).ok_or("Missing task_id")?;
+        match ctx.task_manager.results.latest(task_id).await? {
+            Some(result) => Ok(result.output),
+            None => Ok(format!("No result for task {}", task_id)),
+        }
+    }
+}
+
+/// Reads the tail of the application log.
+struct ReadRecentLogs;
+
+#[async_trait]
+impl Tool for ReadRecentLogs {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "read_recent_logs".to_string(),
+            description: format!("Read the last lines of the application log, at most {}.", MAX_LOG_LINES),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "lines": { "type": "integer", "description": "Number of lines, default 50" }
+                }
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: &Value, _ctx: &ToolContext<'_>) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let lines = arguments.get("lines").and_then(Value::as_u64).unwrap_or(50) as usize;
+        Ok(read_recent_logs(lines.min(MAX_LOG_LINES))?.join("\n"))
+    }
+}
+
+/// Reports the Redis connection, the current model and the number of tasks per status.
+struct GetStatus;
+
+#[async_trait]
+impl Tool for GetStatus {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "get_status".to_string(),
+            description: "Get the state of the Redis connection, the current LLM model and the number of tasks per status.".to_string(),
+            parameters: json!({ "type": "object", "properties": {} }),
+        }
+    }
+
+    async fn call(&self, _arguments: &Value, ctx: &ToolContext<'_>) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let redis_ok = ctx.task_manager.check_redis_connection().await.is_ok();
+        let mut tasks = serde_json::Map::new();
+        for (status, count) in ctx.task_manager.count_tasks_by_status().await? {
+            tasks.insert(status.as_str().to_string(), json!(count));
+        }
+        Ok(json!({
+            "redis": if redis_ok { "OK" } else { "Failed" },
+            "model": ctx.llm_client.model(),
+            "tasks": tasks,
+        }).to_string())
+    }
+}
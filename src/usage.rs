@@ -0,0 +1,249 @@
+use chrono::{DateTime, Utc};
+use log::error;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::time::Duration;
+use crate::llm_client::LLMResponse;
+use crate::task_manager::Task;
+
+/// Number of individual calls kept in `usage:calls`.
+const RECENT_CALLS_LIMIT: isize = 1000;
+
+/// How long the totals of a task are kept after its last call.
+const TASK_TOTALS_TTL_SECS: i64 = 7 * 24 * 3600;
+
+/// How long the totals of an hour are kept.
+const HOUR_TOTALS_TTL_SECS: i64 = 30 * 24 * 3600;
+
+/// Action recorded for LLM calls made through the API rather than by a task.
+pub const API_ACTION: &str = "api";
+
+/// One LLM call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub model: String,
+    pub action: String,
+    pub task_id: Option<String>,
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    /// Wall time of the call, as reported by the server when it does.
+    pub total_duration_ms: u64,
+    /// Time spent loading the model before generating.
+    pub load_duration_ms: Option<u64>,
+    /// Time spent generating the completion tokens.
+    pub eval_duration_ms: Option<u64>,
+    pub at: DateTime<Utc>,
+}
+
+impl UsageRecord {
+    /// A call of `model` made for `task`, or through the API when `task` is `None`,
+    /// that took `elapsed`.
+    pub fn new(model: &str, task: Option<&Task>, elapsed: Duration) -> Self {
+        UsageRecord {
+            model: model.to_string(),
+            action: task.map_or(API_ACTION, |task| task.action.as_str()).to_string(),
+            task_id: task.map(|task| task.id.clone()),
+            prompt_tokens: None,
+            completion_tokens: None,
+            total_duration_ms: elapsed.as_millis() as u64,
+            load_duration_ms: None,
+            eval_duration_ms: None,
+            at: Utc::now(),
+        }
+    }
+
+    /// Takes the model, token counts and durations reported by the server.
+    pub fn with_response(mut self, response: &LLMResponse) -> Self {
+        self.model = response.model.clone();
+        self.prompt_tokens = response.prompt_tokens;
+        self.completion_tokens = response.completion_tokens;
+        self.total_duration_ms = response.total_duration_ms.unwrap_or(self.total_duration_ms);
+        self.load_duration_ms = response.load_duration_ms;
+        self.eval_duration_ms = response.eval_duration_ms;
+        self
+    }
+
+    pub fn tokens_per_sec(&self) -> Option<f64> {
+        let completion_tokens = self.completion_tokens?;
+        let duration_ms = self.eval_duration_ms.unwrap_or(self.total_duration_ms);
+        if duration_ms == 0 {
+            return None;
+        }
+        Some(completion_tokens as f64 * 1000.0 / duration_ms as f64)
+    }
+}
+
+/// Usage summed over every call of one task, action, model or hour.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageTotals {
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_duration_ms: u64,
+    pub load_duration_ms: u64,
+    pub eval_duration_ms: u64,
+    /// Completion tokens per second of generation time.
+    pub tokens_per_sec: f64,
+}
+
+impl UsageTotals {
+    fn from_fields(fields: HashMap<String, u64>) -> Self {
+        let field = |name: &str| fields.get(name).copied().unwrap_or(0);
+        let eval_duration_ms = field("eval_duration_ms");
+        UsageTotals {
+            calls: field("calls"),
+            prompt_tokens: field("prompt_tokens"),
+            completion_tokens: field("completion_tokens"),
+            total_duration_ms: field("total_duration_ms"),
+            load_duration_ms: field("load_duration_ms"),
+            eval_duration_ms,
+            tokens_per_sec: if eval_duration_ms > 0 {
+                field("timed_completion_tokens") as f64 * 1000.0 / eval_duration_ms as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Usage aggregated per dimension, keyed by task ID, action, model and hour (`YYYY-MM-DDTHH`).
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub by_task: BTreeMap<String, UsageTotals>,
+    pub by_action: BTreeMap<String, UsageTotals>,
+    pub by_model: BTreeMap<String, UsageTotals>,
+    pub by_hour: BTreeMap<String, UsageTotals>,
+}
+
+/// Records every LLM call and keeps running totals per task, action, model and hour.
+#[derive(Clone, Debug)]
+pub struct UsageStore {
+    redis_client: Client,
+}
+
+fn totals_key(dimension: &str, name: &str) -> String {
+    format!("usage:{}:{}", dimension, name)
+}
+
+fn index_key(dimension: &str) -> String {
+    format!("usage:index:{}", dimension)
+}
+
+/// How long the totals of `dimension` are kept; actions and models are kept forever.
+fn totals_ttl(dimension: &str) -> Option<i64> {
+    match dimension {
+        "task" => Some(TASK_TOTALS_TTL_SECS),
+        "hour" => Some(HOUR_TOTALS_TTL_SECS),
+        _ => None,
+    }
+}
+
+impl UsageStore {
+    pub fn new(redis_client: Client) -> Self {
+        UsageStore { redis_client }
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        self.redis_client.get_multiplexed_async_connection().await
+    }
+
+    /// Records `usage`, logging instead of failing so accounting never breaks an LLM call.
+    pub async fn record(&self, usage: &UsageRecord) {
+        if let Err(e) = self.try_record(usage).await {
+            error!("Failed to record LLM usage: {:?}", e);
+        }
+    }
+
+    async fn try_record(&self, usage: &UsageRecord) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let hour = usage.at.format("%Y-%m-%dT%H").to_string();
+        let mut names = vec![("action", usage.action.as_str()), ("model", usage.model.as_str()), ("hour", hour.as_str())];
+        if let Some(task_id) = &usage.task_id {
+            names.push(("task", task_id.as_str()));
+        }
+        // Only calls that report their generation time count towards tokens/sec
+        let timed_completion_tokens = match usage.eval_duration_ms {
+            Some(_) => usage.completion_tokens.unwrap_or(0),
+            None => 0,
+        };
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (dimension, name) in names {
+            let key = totals_key(dimension, name);
+            pipe.sadd(index_key(dimension), name).ignore()
+                .hincr(&key, "calls", 1).ignore()
+                .hincr(&key, "prompt_tokens", usage.prompt_tokens.unwrap_or(0)).ignore()
+                .hincr(&key, "completion_tokens", usage.completion_tokens.unwrap_or(0)).ignore()
+                .hincr(&key, "total_duration_ms", usage.total_duration_ms).ignore()
+                .hincr(&key, "load_duration_ms", usage.load_duration_ms.unwrap_or(0)).ignore()
+                .hincr(&key, "eval_duration_ms", usage.eval_duration_ms.unwrap_or(0)).ignore()
+                .hincr(&key, "timed_completion_tokens", timed_completion_tokens).ignore();
+            if let Some(ttl) = totals_ttl(dimension) {
+                pipe.expire(&key, ttl).ignore();
+            }
+        }
+        pipe.lpush("usage:calls", serde_json::to_string(usage)?).ignore()
+            .ltrim("usage:calls", 0, RECENT_CALLS_LIMIT - 1).ignore();
+
+        let mut con = self.connection().await?;
+        let _: () = pipe.query_async(&mut con).await?;
+        Ok(())
+    }
+
+    /// Totals of every name of `dimension`. Names whose totals have expired
+    /// are removed from the index.
+    async fn totals(&self, con: &mut MultiplexedConnection, dimension: &str) -> Result<BTreeMap<String, UsageTotals>, Box<dyn Error + Send + Sync>> {
+        let names: Vec<String> = con.smembers(index_key(dimension)).await?;
+        if names.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        let mut pipe = redis::pipe();
+        for name in &names {
+            pipe.hgetall(totals_key(dimension, name));
+        }
+        let all_fields: Vec<HashMap<String, u64>> = pipe.query_async(con).await?;
+
+        let mut totals = BTreeMap::new();
+        let mut expired = Vec::new();
+        for (name, fields) in names.into_iter().zip(all_fields) {
+            if fields.is_empty() {
+                expired.push(name);
+            } else {
+                totals.insert(name, UsageTotals::from_fields(fields));
+            }
+        }
+        if !expired.is_empty() {
+            let _: () = con.srem(index_key(dimension), expired).await?;
+        }
+        Ok(totals)
+    }
+
+    pub async fn report(&self) -> Result<UsageReport, Box<dyn Error + Send + Sync>> {
+        let mut con = self.connection().await?;
+        Ok(UsageReport {
+            by_task: self.totals(&mut con, "task").await?,
+            by_action: self.totals(&mut con, "action").await?,
+            by_model: self.totals(&mut con, "model").await?,
+            by_hour: self.totals(&mut con, "hour").await?,
+        })
+    }
+
+    /// The `count` most recent calls, newest first.
+    pub async fn recent_calls(&self, count: usize) -> Result<Vec<UsageRecord>, Box<dyn Error + Send + Sync>> {
+        // LRANGE 0 -1 would return the whole list
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut con = self.connection().await?;
+        let last = isize::try_from(count - 1).unwrap_or(isize::MAX);
+        let calls_json: Vec<String> = con.lrange("usage:calls", 0, last).await?;
+        let mut calls = Vec::new();
+        for call_json in calls_json {
+            calls.push(serde_json::from_str(&call_json)?);
+        }
+        Ok(calls)
+    }
+}